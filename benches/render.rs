@@ -0,0 +1,102 @@
+//! Bytes written and time spent per frame by `Renderer::encode`, for a few typical editor screens.
+//! Run with `cargo bench`.
+
+#![allow(dead_code)]
+#![allow(
+    clippy::needless_return,
+    clippy::clone_on_copy,
+    clippy::unnecessary_cast,
+    clippy::explicit_counter_loop,
+    clippy::unit_arg,
+    clippy::unused_unit,
+    clippy::upper_case_acronyms,
+    clippy::inherent_to_string,
+)]
+
+use std::{hint::black_box, time::{Duration, Instant}};
+
+#[path = "../src/renderer.rs"]
+mod renderer;
+
+use renderer::{Color, Renderer, Style};
+
+const WIDTH: u32 = 120;
+const HEIGHT: u32 = 40;
+/// Minimum time spent on each case
+const DURATION: Duration = Duration::from_millis(500);
+
+const SOURCE: &str = include_str!("../src/main.rs");
+
+/** Draws an editor screen: header, gutter, the text scrolled to `scroll` with some highlighting, status bar */
+fn draw(renderer: &mut Renderer, scroll: usize, cursor: (u32, u32), typed: &str) {
+    let text = Style::default().fg(Color::RGB(248, 248, 242)).bg(Color::RGB(40, 42, 54)).clone();
+    let head = Style::default().fg(Color::RGB(248, 248, 242)).bg(Color::RGB(68, 71, 90)).clone();
+    let gutter = Style::default().bg(Color::RGB(98, 114, 164)).clone();
+    let keyword = text.clone().fg(Color::RGB(255, 121, 198)).bold(true).clone();
+    let comment = text.clone().fg(Color::RGB(98, 114, 164)).italic(true).clone();
+
+    renderer.clear();
+    renderer.paint(0, 0, WIDTH, 1, head);
+    renderer.put_text(WIDTH/2 - 4, 0, "main.rs".to_string());
+    renderer.paint(0, 1, 5, HEIGHT-2, gutter);
+    renderer.paint(5, 1, 1, HEIGHT-2, Style::default().fg(Color::RGB(68, 71, 90)).clone());
+    renderer.paint(6, 1, WIDTH-6, HEIGHT-2, text);
+    for (j, line) in SOURCE.lines().skip(scroll).take(HEIGHT as usize - 2).enumerate() {
+        let y = j as u32 + 1;
+        renderer.put_text(0, y, format!("{: >4}", scroll + j + 1));
+        renderer.get_mut(5, y).c = '┃';
+        let line = if y == cursor.1 { format!("{}{}", typed, line) } else { line.to_string() };
+        for (x, c) in line.chars().take(WIDTH as usize - 6).enumerate() {
+            let cell = renderer.get_mut(x as u32 + 6, y);
+            cell.c = if c == '\t' { ' ' } else { c };
+        }
+        let trimmed = line.trim_start();
+        let indent = (line.len() - trimmed.len()) as u32;
+        if trimmed.starts_with("//") || trimmed.starts_with("/*") || trimmed.starts_with('*') {
+            renderer.paint(6 + indent, y, (trimmed.chars().count() as u32).min(WIDTH - 6 - indent), 1, comment);
+        } else {
+            for word in ["fn ", "let ", "pub ", "if ", "return "] {
+                if let Some(i) = line.find(word) {
+                    let x = line[..i].chars().count() as u32 + 6;
+                    if x + word.len() as u32 <= WIDTH {
+                        renderer.paint(x, y, word.len() as u32 - 1, 1, keyword);
+                    }
+                }
+            }
+        }
+    }
+    renderer.paint(0, HEIGHT-1, WIDTH, 1, head);
+    let position = format!("{}:{}", cursor.1 as usize + scroll, cursor.0 + 1);
+    renderer.put_text(WIDTH - 1 - position.len() as u32, HEIGHT-1, position);
+    renderer.get_mut(cursor.0 + 6, cursor.1).s.reverse(true);
+}
+
+/** Measures the frames going from the screen drawn by `before` to the one drawn by `after` */
+fn bench(name: &str, before: impl Fn(&mut Renderer), after: impl Fn(&mut Renderer)) {
+    let mut renderer = Renderer::new();
+    renderer.resize((WIDTH, HEIGHT));
+    before(&mut renderer);
+    renderer.encode();
+    renderer.flip();
+    after(&mut renderer);
+    let bytes = renderer.encode().len();
+
+    let mut frames = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < DURATION {
+        black_box(renderer.encode());
+        frames += 1;
+    }
+    let time = start.elapsed() / frames;
+    println!("{: <16} {: >8} bytes {: >10.2?}/frame", name, bytes, time);
+}
+
+fn main() {
+    bench("full redraw", |r| r.void(), |r| { r.void(); draw(r, 0, (0, 1), ""); });
+    bench("unchanged", |r| draw(r, 0, (0, 1), ""), |r| draw(r, 0, (0, 1), ""));
+    bench("cursor blink", |r| draw(r, 0, (4, 10), ""), |r| { draw(r, 0, (4, 10), ""); r.get_mut(10, 10).s.reverse(false); });
+    bench("typing", |r| draw(r, 0, (4, 10), ""), |r| draw(r, 0, (5, 10), "x"));
+    bench("cursor down", |r| draw(r, 0, (4, 10), ""), |r| draw(r, 0, (4, 11), ""));
+    bench("scroll", |r| draw(r, 100, (0, 1), ""), |r| draw(r, 101, (0, 1), ""));
+    bench("page down", |r| draw(r, 100, (0, 1), ""), |r| draw(r, 138, (0, 1), ""));
+}
@@ -0,0 +1,238 @@
+use termion::event::{self, Event, Key};
+
+/// Sent by the terminal around pasted text, once bracketed paste is enabled
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Sent by the terminal when it loses focus, once focus events are enabled
+pub const FOCUS_OUT: &[u8] = b"\x1b[O";
+
+/** Raw terminal input, read bit by bit, turned into events once they are complete */
+pub struct Parser {
+    /// Input that was read but doesn't make a whole event yet
+    pub bytes: Vec<u8>,
+    /// How far the paste starting `bytes` was already searched for its end, so that a large paste is only searched once
+    searched: usize,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            searched: 0,
+        }
+    }
+
+    /**
+     * Splits the input into events, along with the bytes each of them was parsed from.
+     * Sequences termion doesn't know about are reported as `Event::Unsupported`,
+     * an incomplete escape sequence at the end of the input is left in `bytes` for the next read.
+     * Pasted text comes as a single `Event::Unsupported` holding it between the paste markers (see `pasted`).
+     */
+    pub fn parse(&mut self) -> Vec<(Event, Vec<u8>)> {
+        let bytes = &mut self.bytes;
+        let mut events = Vec::new();
+        let mut i = 0usize;
+        while i < bytes.len() {
+            let rest = &bytes[i..];
+            let len = if rest.starts_with(PASTE_START) {
+                // An incomplete paste is always left at the start of the input
+                let from = if i == 0 { self.searched.saturating_sub(PASTE_END.len() - 1).max(PASTE_START.len()) } else { PASTE_START.len() };
+                match rest[from..].windows(PASTE_END.len()).position(|w| w == PASTE_END) {
+                    Some(end) => {
+                        self.searched = 0;
+                        from + end + PASTE_END.len()
+                    }
+                    None => {
+                        self.searched = rest.len();
+                        break;
+                    }
+                }
+            } else if rest[0] == b'\x1b' && rest.len() > 1 && rest[1] == b'[' {
+                match csi_len(rest) {
+                    Some(len) => len,
+                    None => break,
+                }
+            } else if rest[0] == b'\x1b' && rest.len() > 1 && rest[1] == b'O' {
+                if rest.len() < 3 {
+                    break;
+                }
+                3
+            } else if rest[0] == b'\x1b' && rest.len() > 1 {
+                match char_len(&rest[1..]) {
+                    Some(len) => 1 + len,
+                    None => break,
+                }
+            } else {
+                match char_len(rest) {
+                    Some(len) => len,
+                    None => break,
+                }
+            };
+            let seq = rest[..len].to_vec();
+            events.push((parse_event(&seq), seq));
+            i += len;
+        }
+        bytes.drain(..i);
+        return events;
+    }
+}
+
+/** Whether the sequence of an `Event::Unsupported` is pasted text */
+pub fn is_paste(seq: &[u8]) -> bool {
+    return seq.starts_with(PASTE_START);
+}
+
+/** The text of a paste event, with its line endings turned into `\n` */
+pub fn pasted(seq: &[u8]) -> Option<String> {
+    let text = seq.strip_prefix(PASTE_START)?.strip_suffix(PASTE_END)?;
+    return Some(String::from_utf8_lossy(text).replace("\r\n", "\n").replace('\r', "\n"));
+}
+
+/** The mode and its state in the answer to a DECRQM query (`CSI ? mode ; state $ y`) */
+pub fn mode_report(seq: &[u8]) -> Option<(u32, u8)> {
+    let params = seq.strip_prefix(b"\x1b[?")?.strip_suffix(b"$y")?;
+    let (mode, state) = std::str::from_utf8(params).ok()?.split_once(';')?;
+    return Some((mode.parse().ok()?, state.parse().ok()?));
+}
+
+/** Whether the sequence is the answer to a primary device attributes query (`CSI ? ... c`) */
+pub fn is_device_attributes(seq: &[u8]) -> bool {
+    return seq.starts_with(b"\x1b[?") && seq.ends_with(b"c");
+}
+
+/** Parses a single, complete event */
+fn parse_event(seq: &[u8]) -> Event {
+    if seq == b"\x1b" {
+        return Event::Key(Key::Esc);
+    }
+    if seq.starts_with(PASTE_START) {
+        return Event::Unsupported(seq.to_vec());
+    }
+    if seq.len() > 2 && seq.ends_with(b"~") && seq.starts_with(b"\x1b[") {
+        // termion prints out the codes it doesn't know about, so only let it see the ones it does
+        let known = std::str::from_utf8(&seq[2..seq.len()-1])
+            .ok()
+            .and_then(|n| n.parse::<u8>().ok())
+            .is_some_and(|n| matches!(n, 1..=8 | 11..=15 | 17..=21 | 23 | 24));
+        if !known {
+            return Event::Unsupported(seq.to_vec());
+        }
+    }
+    let mut iter = seq[1..].iter().map(|b| Ok(*b));
+    return event::parse_event(seq[0], &mut iter).unwrap_or(Event::Unsupported(seq.to_vec()));
+}
+
+/** The length of the CSI sequence starting `bytes`, if it is complete */
+fn csi_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.get(2) == Some(&b'M') {
+        // X10 mouse reports carry three raw bytes
+        return (bytes.len() >= 6).then_some(6);
+    }
+    return bytes[2..].iter().position(|b| (0x40..=0x7e).contains(b)).map(|p| p + 3);
+}
+
+/** The length of the UTF-8 character starting with `b` */
+fn utf8_len(b: u8) -> usize {
+    match b {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    }
+}
+
+/**
+ * The length of the UTF-8 character starting `bytes`, if all of it was read.
+ * A malformed character only spans the bytes that fit in it, so that the ones after it aren't swallowed.
+ */
+fn char_len(bytes: &[u8]) -> Option<usize> {
+    let len = utf8_len(bytes[0]);
+    let valid = 1 + bytes[1..].iter().take(len - 1).take_while(|b| **b & 0xc0 == 0x80).count();
+    if valid == len {
+        return Some(len);
+    }
+    if valid == bytes.len() {
+        return None;
+    }
+    return Some(valid);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** Feeds the chunks one read at a time, collecting the events parsed along the way */
+    fn feed(chunks: &[&[u8]]) -> (Vec<Event>, Vec<u8>) {
+        let mut parser = Parser::new();
+        let mut events = Vec::new();
+        for chunk in chunks {
+            parser.bytes.extend_from_slice(chunk);
+            events.extend(parser.parse().into_iter().map(|(ev, _)| ev));
+        }
+        return (events, parser.bytes);
+    }
+
+    #[test]
+    fn parses_keys() {
+        let (events, rest) = feed(&[b"a\x1b[A\x1bOP\x1bx\x7f"]);
+        assert_eq!(events, vec![
+            Event::Key(Key::Char('a')),
+            Event::Key(Key::Up),
+            Event::Key(Key::F(1)),
+            Event::Key(Key::Alt('x')),
+            Event::Key(Key::Backspace),
+        ]);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn lone_escape_is_a_key() {
+        assert_eq!(feed(&[b"\x1b"]).0, vec![Event::Key(Key::Esc)]);
+    }
+
+    #[test]
+    fn keeps_split_characters() {
+        let (events, rest) = feed(&[b"\xc3"]);
+        assert!(events.is_empty());
+        assert_eq!(rest, b"\xc3");
+        assert_eq!(feed(&[b"\xc3", b"\xa9"]).0, vec![Event::Key(Key::Char('é'))]);
+        assert_eq!(feed(&[b"\xe2", b"\x82", b"\xac"]).0, vec![Event::Key(Key::Char('€'))]);
+        assert_eq!(feed(&[b"\x1b\xc3", b"\xa9"]).0, vec![Event::Key(Key::Alt('é'))]);
+    }
+
+    #[test]
+    fn malformed_characters_dont_swallow_what_follows() {
+        let (events, rest) = feed(&[b"\xc3a"]);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1], Event::Key(Key::Char('a')));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn keeps_split_sequences() {
+        assert_eq!(feed(&[b"\x1b[", b"1;5", b"C"]).0, vec![Event::Key(Key::CtrlRight)]);
+        assert_eq!(feed(&[b"\x1bO", b"Q"]).0, vec![Event::Key(Key::F(2))]);
+    }
+
+    #[test]
+    fn pastes_across_reads() {
+        let (events, rest) = feed(&[b"\x1b[200~one\r\ntw", b"o\x1b[2", b"01~x"]);
+        assert_eq!(events.len(), 2);
+        let Event::Unsupported(seq) = &events[0] else {
+            panic!("not a paste: {:?}", events[0]);
+        };
+        assert!(is_paste(seq));
+        assert_eq!(pasted(seq).as_deref(), Some("one\ntwo"));
+        assert_eq!(events[1], Event::Key(Key::Char('x')));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert_eq!(mode_report(b"\x1b[?2026;2$y"), Some((2026, 2)));
+        assert_eq!(mode_report(b"\x1b[?2026$y"), None);
+        assert!(is_device_attributes(b"\x1b[?62;22c"));
+        assert!(!is_device_attributes(b"\x1b[A"));
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::{BTreeMap, HashMap};
+use serde::{Deserialize, Serialize};
+use termion::event::{Event, Key};
+
+/** Something the user can ask a window to do */
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Removes the binding of a key (for use in the settings)
+    None,
+    Menu,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    LineStart,
+    LineEnd,
+    WordLeft,
+    WordRight,
+    PageUp,
+    PageDown,
+    DocumentStart,
+    DocumentEnd,
+    SelectLeft,
+    SelectRight,
+    SelectUp,
+    SelectDown,
+    SelectLineStart,
+    SelectLineEnd,
+    SelectWordLeft,
+    SelectWordRight,
+    ScrollUp,
+    ScrollDown,
+    ScrollLeft,
+    ScrollRight,
+    Tab,
+    Dedent,
+    Newline,
+    Backspace,
+    Delete,
+    DeleteWordBack,
+    DeleteWordForward,
+    MatchingBracket,
+    GotoLine,
+    ToggleWrap,
+    Undo,
+    Redo,
+    Reflow,
+    ToggleAutoWrap,
+    ToggleFold,
+    FoldAll,
+    UnfoldAll,
+    ToggleSpaces,
+    ToggleTabs,
+    ToggleTrailing,
+    ToggleIndentGuides,
+    ToggleEol,
+    ToggleRulers,
+    ToggleBookmark,
+    NextBookmark,
+    /// Stops the editor and goes back to the shell, until it gets resumed (with `fg`)
+    Suspend,
+}
+
+impl Action {
+    /** The movement extending the selection for the selecting actions */
+    pub fn selecting(self) -> Option<Action> {
+        match self {
+            Action::SelectLeft => Some(Action::MoveLeft),
+            Action::SelectRight => Some(Action::MoveRight),
+            Action::SelectUp => Some(Action::MoveUp),
+            Action::SelectDown => Some(Action::MoveDown),
+            Action::SelectLineStart => Some(Action::LineStart),
+            Action::SelectLineEnd => Some(Action::LineEnd),
+            Action::SelectWordLeft => Some(Action::WordLeft),
+            Action::SelectWordRight => Some(Action::WordRight),
+            _ => None,
+        }
+    }
+
+    /** Whether the action moves the cursor across lines, keeping its column */
+    pub fn is_vertical(self) -> bool {
+        matches!(self.selecting().unwrap_or(self), Action::MoveUp | Action::MoveDown | Action::PageUp | Action::PageDown)
+    }
+
+    /** Whether the action changes the body (and should be undoable) */
+    pub fn is_edit(self) -> bool {
+        matches!(self,
+            Action::Tab | Action::Dedent | Action::Newline | Action::Backspace | Action::Delete |
+            Action::DeleteWordBack | Action::DeleteWordForward | Action::Reflow
+        )
+    }
+
+    /** Whether the action only moves the cursor around */
+    pub fn is_motion(self) -> bool {
+        matches!(self,
+            Action::MoveLeft | Action::MoveRight | Action::MoveUp | Action::MoveDown |
+            Action::LineStart | Action::LineEnd | Action::WordLeft | Action::WordRight |
+            Action::PageUp | Action::PageDown | Action::DocumentStart | Action::DocumentEnd |
+            Action::MatchingBracket | Action::NextBookmark
+        )
+    }
+}
+
+/** The bindings used when the settings don't say otherwise */
+pub fn default_bindings() -> BTreeMap<String, Action> {
+    [
+        ("esc", Action::Menu),
+        ("left", Action::MoveLeft),
+        ("right", Action::MoveRight),
+        ("up", Action::MoveUp),
+        ("down", Action::MoveDown),
+        ("home", Action::LineStart),
+        ("end", Action::LineEnd),
+        ("shift-left", Action::SelectLeft),
+        ("shift-right", Action::SelectRight),
+        ("shift-up", Action::SelectUp),
+        ("shift-down", Action::SelectDown),
+        ("shift-home", Action::SelectLineStart),
+        ("shift-end", Action::SelectLineEnd),
+        ("ctrl-left", Action::WordLeft),
+        ("ctrl-right", Action::WordRight),
+        ("ctrl-shift-left", Action::SelectWordLeft),
+        ("ctrl-shift-right", Action::SelectWordRight),
+        ("pageup", Action::PageUp),
+        ("pagedown", Action::PageDown),
+        ("ctrl-home", Action::DocumentStart),
+        ("ctrl-end", Action::DocumentEnd),
+        ("ctrl-up", Action::ScrollUp),
+        ("ctrl-down", Action::ScrollDown),
+        ("alt-left", Action::ScrollLeft),
+        ("alt-right", Action::ScrollRight),
+        ("tab", Action::Tab),
+        ("backtab", Action::Dedent),
+        ("enter", Action::Newline),
+        ("backspace", Action::Backspace),
+        ("delete", Action::Delete),
+        ("ctrl-h", Action::DeleteWordBack),
+        ("alt-backspace", Action::DeleteWordBack),
+        ("ctrl-delete", Action::DeleteWordForward),
+        ("alt-d", Action::DeleteWordForward),
+        ("ctrl-b", Action::MatchingBracket),
+        ("ctrl-g", Action::GotoLine),
+        ("alt-z", Action::ToggleWrap),
+        ("alt-u", Action::Undo),
+        ("alt-e", Action::Redo),
+        ("alt-q", Action::Reflow),
+        ("alt-f", Action::ToggleFold),
+        ("alt-F", Action::FoldAll),
+        ("alt-o", Action::UnfoldAll),
+        ("ctrl-k s", Action::ToggleSpaces),
+        ("ctrl-k t", Action::ToggleTabs),
+        ("ctrl-k w", Action::ToggleTrailing),
+        ("ctrl-k i", Action::ToggleIndentGuides),
+        ("ctrl-k e", Action::ToggleEol),
+        ("ctrl-k r", Action::ToggleRulers),
+        ("alt-m", Action::ToggleBookmark),
+        ("alt-n", Action::NextBookmark),
+        ("ctrl-z", Action::Suspend),
+    ]
+        .into_iter()
+        .map(|(k, a)| (k.to_string(), a))
+        .collect()
+}
+
+/** What a key press resolved to */
+#[derive(Clone, PartialEq, Debug)]
+pub enum Binding {
+    Action(Action),
+    /// The key started (or continued) a chord
+    Pending,
+    /// The keys are not bound to anything
+    Unbound(Vec<Event>),
+}
+
+/**
+ * Maps sequences of key events to actions.
+ * Keys termion can't represent (such as `ctrl-delete`) are bound through the raw sequence of `Event::Unsupported`.
+ */
+pub struct KeyMap {
+    bindings: HashMap<Vec<Event>, Action>,
+    prefixes: HashMap<Vec<Event>, usize>,
+    pub pending: Vec<Event>,
+}
+
+impl KeyMap {
+    /** Builds a key map from the default bindings, overridden by `keys` */
+    pub fn new(keys: &BTreeMap<String, Action>) -> Result<Self, String> {
+        let mut map = Self {
+            bindings: HashMap::new(),
+            prefixes: HashMap::new(),
+            pending: Vec::new(),
+        };
+        for (seq, action) in default_bindings().iter().chain(keys.iter()) {
+            let keys = parse_keys(seq).ok_or(format!("Invalid key binding '{}'", seq))?;
+            map.bind(keys, *action);
+        }
+        return Ok(map);
+    }
+
+    /** Binds (or unbinds, with `Action::None`) a sequence of keys */
+    pub fn bind(&mut self, keys: Vec<Event>, action: Action) {
+        let old = if action == Action::None {
+            self.bindings.remove(&keys)
+        } else {
+            self.bindings.insert(keys.clone(), action)
+        };
+        let delta: isize = match (old.is_some(), action != Action::None) {
+            (false, true) => 1,
+            (true, false) => -1,
+            _ => 0,
+        };
+        if delta == 0 {
+            return;
+        }
+        for i in 1 .. keys.len() {
+            let count = self.prefixes.entry(keys[..i].to_vec()).or_insert(0);
+            *count = count.saturating_add_signed(delta);
+            if *count == 0 {
+                self.prefixes.remove(&keys[..i]);
+            }
+        }
+    }
+
+    /** Feeds a key press, resolving it once it completes a binding */
+    pub fn feed(&mut self, key: Event) -> Binding {
+        let key = match key {
+            Event::Key(Key::Char('\r')) => Event::Key(Key::Char('\n')),
+            key => key,
+        };
+        self.pending.push(key);
+        if let Some(action) = self.bindings.get(&self.pending) {
+            self.pending.clear();
+            return Binding::Action(*action);
+        }
+        if self.prefixes.contains_key(&self.pending) {
+            return Binding::Pending;
+        }
+        return Binding::Unbound(std::mem::take(&mut self.pending));
+    }
+}
+
+/** Parses a space-separated sequence of keys such as `ctrl-k ctrl-c` */
+pub fn parse_keys(seq: &str) -> Option<Vec<Event>> {
+    let keys = seq.split_whitespace().map(parse_key).collect::<Option<Vec<Event>>>()?;
+    if keys.is_empty() {
+        return None;
+    }
+    return Some(keys);
+}
+
+const CTRL: u8 = 4;
+const ALT: u8 = 2;
+const SHIFT: u8 = 1;
+
+/** Parses the name of a key without modifiers */
+fn parse_plain_key(name: &str) -> Option<Key> {
+    Some(match name {
+        "esc" => Key::Esc,
+        "enter" => Key::Char('\n'),
+        "tab" => Key::Char('\t'),
+        "backtab" => Key::BackTab,
+        "space" => Key::Char(' '),
+        "backspace" => Key::Backspace,
+        "delete" => Key::Delete,
+        "insert" => Key::Insert,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        "null" => Key::Null,
+        _ => {
+            if let Some(n) = name.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                return (1..=12).contains(&n).then_some(Key::F(n));
+            }
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Key::Char(c),
+                _ => return None,
+            }
+        }
+    })
+}
+
+/** Parses a single key such as `a`, `pagedown`, `shift-left` or `ctrl-alt-delete` */
+pub fn parse_key(name: &str) -> Option<Event> {
+    if name.chars().count() == 1 {
+        return parse_plain_key(name).map(Event::Key);
+    }
+    let mut mods = 0u8;
+    let mut rest = name;
+    loop {
+        if let Some(r) = rest.strip_prefix("ctrl-") {
+            mods |= CTRL;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("alt-") {
+            mods |= ALT;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("shift-") {
+            mods |= SHIFT;
+            rest = r;
+        } else {
+            break;
+        }
+    }
+    let key = parse_plain_key(rest)?;
+    let termion = match (mods, key) {
+        (0, key) => Some(key),
+        (CTRL, Key::Left) => Some(Key::CtrlLeft),
+        (CTRL, Key::Right) => Some(Key::CtrlRight),
+        (CTRL, Key::Up) => Some(Key::CtrlUp),
+        (CTRL, Key::Down) => Some(Key::CtrlDown),
+        (CTRL, Key::Home) => Some(Key::CtrlHome),
+        (CTRL, Key::End) => Some(Key::CtrlEnd),
+        (CTRL, Key::Char(c)) if c.is_ascii_graphic() => Some(Key::Ctrl(c.to_ascii_lowercase())),
+        (ALT, Key::Left) => Some(Key::AltLeft),
+        (ALT, Key::Right) => Some(Key::AltRight),
+        (ALT, Key::Up) => Some(Key::AltUp),
+        (ALT, Key::Down) => Some(Key::AltDown),
+        (ALT, Key::Backspace) => Some(Key::Alt('\x7f')),
+        (ALT, Key::Char(c)) => Some(Key::Alt(c)),
+        (SHIFT, Key::Left) => Some(Key::ShiftLeft),
+        (SHIFT, Key::Right) => Some(Key::ShiftRight),
+        (SHIFT, Key::Up) => Some(Key::ShiftUp),
+        (SHIFT, Key::Down) => Some(Key::ShiftDown),
+        (SHIFT, Key::Char('\t')) => Some(Key::BackTab),
+        (SHIFT, Key::Char(c)) if c.is_alphabetic() => Some(Key::Char(c.to_ascii_uppercase())),
+        _ => None,
+    };
+    if let Some(key) = termion {
+        return Some(Event::Key(key));
+    }
+    return xterm_sequence(mods, key).map(Event::Unsupported);
+}
+
+/** The sequence xterm sends for a key with modifiers */
+fn xterm_sequence(mods: u8, key: Key) -> Option<Vec<u8>> {
+    let m = mods + 1;
+    let seq = match key {
+        Key::Up => format!("\x1b[1;{}A", m),
+        Key::Down => format!("\x1b[1;{}B", m),
+        Key::Right => format!("\x1b[1;{}C", m),
+        Key::Left => format!("\x1b[1;{}D", m),
+        Key::End => format!("\x1b[1;{}F", m),
+        Key::Home => format!("\x1b[1;{}H", m),
+        Key::Insert => format!("\x1b[2;{}~", m),
+        Key::Delete => format!("\x1b[3;{}~", m),
+        Key::PageUp => format!("\x1b[5;{}~", m),
+        Key::PageDown => format!("\x1b[6;{}~", m),
+        _ => return None,
+    };
+    return Some(seq.into_bytes());
+}
+
+/** The name of a key event, as understood by `parse_key` */
+pub fn event_name(event: &Event) -> String {
+    match event {
+        Event::Key(key) => key_name(*key),
+        Event::Unsupported(seq) => {
+            let names = [
+                ("A", "up"), ("B", "down"), ("C", "right"), ("D", "left"), ("F", "end"), ("H", "home"),
+                ("2", "insert"), ("3", "delete"), ("5", "pageup"), ("6", "pagedown"),
+            ];
+            let s = String::from_utf8_lossy(seq);
+            let Some(s) = s.strip_prefix("\x1b[") else {
+                return "?".to_string();
+            };
+            let (params, last) = s.split_at(s.len().saturating_sub(1));
+            let params = params.split(';').collect::<Vec<&str>>();
+            let code = if last == "~" { params[0] } else { last };
+            let key = names.iter().find(|(c, _)| *c == code).map(|(_, name)| name);
+            let mods = params.get(1).and_then(|m| m.parse::<u8>().ok()).filter(|m| *m > 1).map(|m| m-1);
+            match (key, mods) {
+                (Some(key), Some(m)) => format!("{}{}{}{}",
+                    if m & CTRL != 0 { "ctrl-" } else { "" },
+                    if m & ALT != 0 { "alt-" } else { "" },
+                    if m & SHIFT != 0 { "shift-" } else { "" },
+                    key
+                ),
+                _ => "?".to_string(),
+            }
+        }
+        _ => "?".to_string(),
+    }
+}
+
+/** The name of a key, as understood by `parse_key` */
+pub fn key_name(key: Key) -> String {
+    match key {
+        Key::Esc => "esc".to_string(),
+        Key::Char('\n') => "enter".to_string(),
+        Key::Char('\t') => "tab".to_string(),
+        Key::Char(' ') => "space".to_string(),
+        Key::Char(c) => c.to_string(),
+        Key::BackTab => "backtab".to_string(),
+        Key::Backspace => "backspace".to_string(),
+        Key::Delete => "delete".to_string(),
+        Key::Insert => "insert".to_string(),
+        Key::Left => "left".to_string(),
+        Key::Right => "right".to_string(),
+        Key::Up => "up".to_string(),
+        Key::Down => "down".to_string(),
+        Key::Home => "home".to_string(),
+        Key::End => "end".to_string(),
+        Key::PageUp => "pageup".to_string(),
+        Key::PageDown => "pagedown".to_string(),
+        Key::CtrlLeft => "ctrl-left".to_string(),
+        Key::CtrlRight => "ctrl-right".to_string(),
+        Key::CtrlUp => "ctrl-up".to_string(),
+        Key::CtrlDown => "ctrl-down".to_string(),
+        Key::CtrlHome => "ctrl-home".to_string(),
+        Key::CtrlEnd => "ctrl-end".to_string(),
+        Key::AltLeft => "alt-left".to_string(),
+        Key::AltRight => "alt-right".to_string(),
+        Key::AltUp => "alt-up".to_string(),
+        Key::AltDown => "alt-down".to_string(),
+        Key::ShiftLeft => "shift-left".to_string(),
+        Key::ShiftRight => "shift-right".to_string(),
+        Key::ShiftUp => "shift-up".to_string(),
+        Key::ShiftDown => "shift-down".to_string(),
+        Key::F(n) => format!("f{}", n),
+        Key::Ctrl(c) => format!("ctrl-{}", c),
+        Key::Alt(c) => format!("alt-{}", c),
+        Key::Null => "null".to_string(),
+        _ => "?".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(parse_keys("ctrl-k ctrl-c"), Some(vec![Event::Key(Key::Ctrl('k')), Event::Key(Key::Ctrl('c'))]));
+        assert_eq!(parse_keys("shift-tab"), Some(vec![Event::Key(Key::BackTab)]));
+        assert_eq!(parse_keys("alt-backspace"), Some(vec![Event::Key(Key::Alt('\x7f'))]));
+        assert_eq!(parse_keys("ctrl-delete"), Some(vec![Event::Unsupported(b"\x1b[3;5~".to_vec())]));
+        assert_eq!(parse_keys(""), None);
+        assert_eq!(parse_keys("ctrl-"), None);
+        assert_eq!(parse_keys("hyper-a"), None);
+        assert_eq!(parse_keys("f13"), None);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for name in ["a", "enter", "ctrl-k", "alt-left", "ctrl-delete", "ctrl-shift-left", "alt-shift-pagedown"] {
+            let key = parse_key(name).unwrap();
+            assert_eq!(parse_key(&event_name(&key)), Some(key), "{}", name);
+        }
+    }
+
+    #[test]
+    fn resolves_chords() {
+        let mut map = KeyMap::new(&BTreeMap::new()).unwrap();
+        assert_eq!(map.feed(Event::Key(Key::Ctrl('k'))), Binding::Pending);
+        assert_eq!(map.pending, vec![Event::Key(Key::Ctrl('k'))]);
+        assert_eq!(map.feed(Event::Key(Key::Char('s'))), Binding::Action(Action::ToggleSpaces));
+        assert!(map.pending.is_empty());
+        assert_eq!(map.feed(Event::Key(Key::Char('x'))), Binding::Unbound(vec![Event::Key(Key::Char('x'))]));
+        assert_eq!(map.feed(Event::Key(Key::Char('\r'))), Binding::Action(Action::Newline));
+    }
+
+    #[test]
+    fn settings_override_defaults() {
+        let keys = [
+            ("ctrl-k s".to_string(), Action::None),
+            ("ctrl-x ctrl-s".to_string(), Action::Undo),
+        ].into_iter().collect::<BTreeMap<String, Action>>();
+        let mut map = KeyMap::new(&keys).unwrap();
+        assert_eq!(map.feed(Event::Key(Key::Ctrl('k'))), Binding::Pending);
+        assert_eq!(map.feed(Event::Key(Key::Char('s'))), Binding::Unbound(vec![Event::Key(Key::Ctrl('k')), Event::Key(Key::Char('s'))]));
+        assert_eq!(map.feed(Event::Key(Key::Ctrl('x'))), Binding::Pending);
+        assert_eq!(map.feed(Event::Key(Key::Ctrl('s'))), Binding::Action(Action::Undo));
+        let bad = [("ctrl-".to_string(), Action::Undo)].into_iter().collect::<BTreeMap<String, Action>>();
+        assert!(KeyMap::new(&bad).is_err());
+    }
+}
@@ -1,14 +1,37 @@
 #![allow(unused_mut,dead_code)]
-
-use core::panic;
-use std::{collections::VecDeque, env::{args, current_dir}, fs, io::{stdin, stdout, Write}, path::{self, Path}, sync::{Arc, Mutex}, thread::{sleep, spawn}, time::{Duration, Instant}};
-
-use libc::{self, termios};
+#![allow(
+    clippy::needless_return,
+    clippy::clone_on_copy,
+    clippy::len_zero,
+    clippy::unnecessary_cast,
+    clippy::bind_instead_of_map,
+    clippy::explicit_counter_loop,
+    clippy::needless_range_loop,
+    clippy::single_match,
+    clippy::unit_arg,
+    clippy::unused_unit,
+    clippy::unwrap_or_default,
+    clippy::unnecessary_to_owned,
+    clippy::upper_case_acronyms,
+    clippy::inherent_to_string,
+    clippy::collapsible_else_if,
+    clippy::collapsible_match,
+)]
+
+use std::{panic::{self, AssertUnwindSafe}, cell::{Cell, OnceCell, RefCell}, collections::{BTreeMap, BTreeSet, HashMap}, env::{args, current_dir}, fs, io::{stdout, Write}, ops::Range, path::{self, Path}, rc::Rc, time::{Duration, Instant}};
+
+use keymap::{Action, Binding, KeyMap};
 use renderer::{Color, Renderer, Style};
+use terminal::Terminal;
 use serde::{Deserialize, Serialize};
-use termion::{event::{Event, Key}, input::TermReadEventsAndRaw};
+use termion::event::{Event, Key, MouseButton, MouseEvent};
+use unicode_width::UnicodeWidthChar;
 
+mod input;
+mod keymap;
+mod recovery;
 mod renderer;
+mod terminal;
 
 const BACKGROUND : Color = Color::RGB(40, 42, 54);
 const HEAD : Color = Color::RGB(68, 71, 90);
@@ -17,10 +40,26 @@ const FOREGROUND : Color = Color::RGB(248, 248, 242);
 const COMMENT : Color = Color::RGB(98, 114, 164);
 const RED : Color = Color::RGB(255, 85, 85);
 const YELLOW : Color = Color::RGB(241, 250, 140);
+const SELECTION : Color = Color::RGB(68, 71, 90);
 
 const BLINK_HOLD : Duration = Duration::from_millis(200);
+/// How long the cursor stays on, then off, when blinking
+const BLINK_RATE : Duration = Duration::from_millis(500);
+
+/// Longest delay between the clicks of a double click
+const DOUBLE_CLICK : Duration = Duration::from_millis(400);
+/// Number of rows scrolled by each step of the mouse wheel
+const WHEEL_ROWS : usize = 3;
+
+const SETTINGS_PATH : &str = "./settings.toml";
+
+const UNDO_LIMIT : usize = 1000;
+
+/// Number of columns of text covered by each dot of the minimap
+const MINIMAP_SCALE : usize = 4;
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 struct ColorSettings {
     background: Color,
     head: Color,
@@ -31,21 +70,263 @@ struct ColorSettings {
     red: Color,
     // green: Color,
     yellow: Color,
+    /// Visible spaces and tabs
+    whitespace: Color,
+    /// Background of trailing whitespace
+    trailing: Color,
+    indent_guide: Color,
+    /// End of line markers
+    eol: Color,
+    /// Background of the column rulers
+    ruler: Color,
+    scrollbar: Color,
+    /// Part of the scrollbar standing for what is in view
+    scrollbar_thumb: Color,
+    /// Scrollbar markers of the lines changed since the file was last saved
+    modified: Color,
+    minimap: Color,
+    /// Background of the part of the minimap in view
+    minimap_view: Color,
     // blue: Color,
     // magenta: Color,
     // cyan: Color,
     // white: Color,
 }
 
+impl Default for ColorSettings {
+    fn default() -> Self {
+        Self {
+            background: Color::RGB(40, 42, 54),
+            head: Color::RGB(68, 71, 90),
+            current: Color::RGB(50, 52, 64),
+            foreground: Color::RGB(248, 248, 242),
+            comment: Color::RGB(98, 114, 164),
+            red: Color::RGB(255, 85, 85),
+            yellow: Color::RGB(241, 250, 140),
+            whitespace: Color::RGB(68, 71, 90),
+            trailing: Color::RGB(110, 50, 60),
+            indent_guide: Color::RGB(68, 71, 90),
+            eol: Color::RGB(98, 114, 164),
+            ruler: Color::RGB(55, 57, 72),
+            scrollbar: Color::RGB(50, 52, 64),
+            scrollbar_thumb: Color::RGB(68, 71, 90),
+            modified: Color::RGB(80, 250, 123),
+            minimap: Color::RGB(98, 114, 164),
+            minimap_view: Color::RGB(50, 52, 64),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 struct ControlSettings {
     smart_home: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Default for ControlSettings {
+    fn default() -> Self {
+        Self {
+            smart_home: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+struct EditorSettings {
+    /// Number of columns between two tab stops
+    tab_width: usize,
+    /// Whether the Tab key inserts spaces instead of a tab character
+    expand_tab: bool,
+    /// Whether to guess `tab_width` and `expand_tab` from the contents of loaded files
+    detect_indent: bool,
+    /// Whether new lines keep the indentation of the line they were split from
+    auto_indent: bool,
+    /// Whether typing an opening bracket or a quote also inserts its closing counterpart
+    auto_pair: bool,
+    /// Number of lines kept visible above and below the cursor
+    scroll_off: usize,
+    /// Number of columns kept visible left and right of the cursor
+    side_scroll_off: usize,
+    /// Whether long lines are wrapped to the width of the view
+    soft_wrap: bool,
+    /// File extensions for which long lines are wrapped regardless of `soft_wrap`
+    soft_wrap_filetypes: Vec<String>,
+    /// Column at which paragraphs get reflowed
+    text_width: usize,
+    /// Whether lines get broken as the text typed goes past `text_width`
+    auto_wrap: bool,
+    /// Line endings (per file extension) after which a new line gets indented one level deeper
+    indent_triggers: HashMap<String, Vec<String>>,
+    /// How lines are numbered in the gutter
+    line_numbers: LineNumbers,
+    /// When to show the column of the gutter holding signs (such as bookmarks)
+    sign_column: SignColumn,
+    /// Columns (counting from 1) highlighted as rulers
+    rulers: Vec<usize>,
+    /// What gets drawn over the text, can be toggled for each buffer
+    show: ShowSettings,
+    /// Whether a scrollbar is shown on the right of the view
+    scrollbar: bool,
+    /// Width (in columns) of the minimap shown left of the scrollbar, 0 to hide it
+    minimap_width: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+struct ShowSettings {
+    /// Whether spaces are drawn as `·`
+    spaces: bool,
+    /// Whether tabs are drawn as `→`
+    tabs: bool,
+    /// Whether whitespace at the end of lines (other than the one of the cursor) gets highlighted
+    trailing: bool,
+    /// Whether vertical lines mark the indentation levels
+    indent_guides: bool,
+    /// Whether the end of lines is marked with `¬`
+    eol: bool,
+    /// Whether the columns of `rulers` are highlighted
+    rulers: bool,
+}
+
+impl Default for ShowSettings {
+    fn default() -> Self {
+        Self {
+            spaces: false,
+            tabs: false,
+            trailing: true,
+            indent_guides: false,
+            eol: false,
+            rulers: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum LineNumbers {
+    /// No line numbers
+    None,
+    /// The number of each line
+    Absolute,
+    /// The distance of each line to the one of the cursor
+    Relative,
+    /// Relative numbers, except for the line of the cursor which gets its own
+    Hybrid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum SignColumn {
+    /// Only when the buffer has signs
+    Auto,
+    Always,
+    Never,
+}
+
+impl Default for EditorSettings {
+    fn default() -> Self {
+        Self {
+            tab_width: 4,
+            expand_tab: true,
+            detect_indent: true,
+            auto_indent: true,
+            auto_pair: true,
+            scroll_off: 3,
+            side_scroll_off: 8,
+            soft_wrap: false,
+            soft_wrap_filetypes: vec!["md".to_string(), "txt".to_string()],
+            text_width: 80,
+            auto_wrap: false,
+            indent_triggers: HashMap::from([
+                ("py".to_string(), vec![":".to_string()]),
+                ("lua".to_string(), vec!["then".to_string(), "do".to_string(), "else".to_string(), "function()".to_string()]),
+                ("sh".to_string(), vec!["then".to_string(), "do".to_string(), "else".to_string()]),
+                ("rb".to_string(), vec!["do".to_string(), "else".to_string()]),
+            ]),
+            line_numbers: LineNumbers::Absolute,
+            sign_column: SignColumn::Auto,
+            rulers: Vec::new(),
+            show: ShowSettings::default(),
+            scrollbar: true,
+            minimap_width: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 struct Settings {
     color: ColorSettings,
     controls: ControlSettings,
+    editor: EditorSettings,
+    autosave: AutosaveSettings,
+    terminal: TerminalSettings,
+    /// Key sequences (such as `ctrl-k ctrl-c`) bound to actions, on top of the default ones
+    keys: BTreeMap<String, Action>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+struct AutosaveSettings {
+    /// Whether modified buffers get saved to swap files (next to their file), to recover from later on
+    swap: bool,
+    /// Seconds between two writes of the swap files, and two checks for idle buffers
+    interval: u64,
+    /// Number of edits after which the swap file gets written without waiting for the interval
+    swap_edits: usize,
+    /// Whether modified files are written once they went `idle_delay` seconds without edits
+    write_when_idle: bool,
+    idle_delay: u64,
+    /// Whether modified files are written when the terminal loses focus
+    write_on_focus_lost: bool,
+}
+
+impl Default for AutosaveSettings {
+    fn default() -> Self {
+        Self {
+            swap: true,
+            interval: 4,
+            swap_edits: 200,
+            write_when_idle: false,
+            idle_delay: 10,
+            write_on_focus_lost: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+struct TerminalSettings {
+    /// Whether frames are drawn as synchronized updates, to keep terminals from showing them half drawn
+    synchronized_output: SynchronizedOutput,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum SynchronizedOutput {
+    /// When the terminal says it supports them
+    Auto,
+    Always,
+    Never,
+}
+
+impl Default for TerminalSettings {
+    fn default() -> Self {
+        Self {
+            synchronized_output: SynchronizedOutput::Auto,
+        }
+    }
+}
+
+impl Settings {
+    /** Reads the settings from the given file, missing entries (or a missing file) fall back to the defaults */
+    fn load(path: &str) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(s) => toml::from_str(&s).map_err(|err| err.to_string()),
+            Err(_) => Ok(Self::default()),
+        }
+    }
 }
 
 struct Clock {
@@ -73,6 +354,18 @@ impl Clock {
 trait Window {
     fn render(&mut self, env: &mut Env, renderer: &mut Renderer) -> ();
     fn key_pressed(&mut self, env: &mut Env, ev: Event) -> ();
+    /** The path and contents of the window, if it has changes that were not saved */
+    fn unsaved(&self) -> Option<(Option<&str>, &str)>;
+    /** Called at the autosave interval, for the background work of the window */
+    fn tick(&mut self, env: &mut Env) -> ();
+    /** Called when the editor quits normally */
+    fn close(&mut self) -> ();
+    /** Called when the screen changes size, before the next render */
+    fn resized(&mut self, env: &mut Env, width: u32, height: u32) -> ();
+    /** When the window will look different without anything happening (such as the cursor blinking), if ever */
+    fn next_frame(&self) -> Option<Instant>;
+    /** Whether the window changed since it was last rendered */
+    fn needs_redraw(&self) -> bool;
 }
 
 #[derive(Clone)]
@@ -85,6 +378,9 @@ enum BufferMenuState {
     Overwrite(String),
     Load(String),
     ForceLoad,
+    Goto(String),
+    /// Holds the recovery copy of the file left by a crash, or the swap file left by another editor
+    Recover(String),
 }
 
 impl BufferMenuState {
@@ -99,10 +395,119 @@ struct Buffer {
     saved: bool,
     path: Option<String>,
     hold_blink: Instant,
+    tab_width: usize,
+    expand_tab: bool,
+    selection: Option<(i32, i32)>,
+    size: (u32, u32),
+    /// Display column vertical movement tries to go back to
+    desired_col: Option<usize>,
+    wrap: bool,
+    auto_wrap: bool,
+    /// Steps that can be undone, and the ones that were undone, each made of the changes it went through in order
+    undo: Vec<Vec<Change>>,
+    redo: Vec<Vec<Change>>,
+    /// Whether the next edit starts a new step, rather than being undone along with the last one
+    new_step: bool,
+    /// Whether the last edit was typing text, which gets undone in one go
+    typing: bool,
+    /// Folded ranges of lines, the first one staying visible to stand for the others
+    folds: Vec<(i32, i32)>,
+    /// Signs shown in the gutter, along with the line they are on
+    signs: Vec<(i32, Sign)>,
+    show: ShowSettings,
+    /// Lines changed since the file was last saved or loaded
+    modified: BTreeSet<i32>,
+    /// Size of the whole window, as of the last render
+    window: (u32, u32),
+    /// When and where the last click in the text happened, to spot double clicks
+    last_click: Option<(Instant, (i32, i32))>,
+    drag: Option<Drag>,
+    /// Number of edits since the swap file was last written
+    edits: usize,
+    last_edit: Instant,
+    /// Byte offset at which each line of the body starts, worked out on demand and then kept up to date by edits
+    line_starts: OnceCell<Vec<usize>>,
+    /// The rows of the view as last worked out, along with what they were worked out from
+    view: RefCell<Option<ViewCache>>,
+    /// Goes up with every edit of the body
+    version: usize,
+    /// The bracket pair around the cursor, as last found
+    brackets: Cell<Option<(usize, usize)>>,
+    /// The version of the body and the cursor `brackets` was found for
+    brackets_at: Cell<Option<(usize, (i32, i32))>>,
+    /// Whether something changed since the last render
+    redraw: bool,
+    /// Whether the cursor was drawn on in the last render, to redraw it when it blinks
+    blink: bool,
+}
+
+/** What a mouse drag started on */
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Drag {
+    /// Selects text
+    Text,
+    /// Scrolls the view (on the scrollbar or the minimap)
+    Scrollbar,
+}
+
+/** An edit of the body, holding what it replaced so that it can be reverted */
+#[derive(Clone, Debug)]
+struct Change {
+    /// Byte range of the body the edit produced
+    start: usize,
+    end: usize,
+    /// The text the range held before the edit
+    text: String,
+    /// Where the cursor was before the edit
+    cursor: (i32, i32),
+}
+
+/** A mark drawn in the sign column of the gutter, next to a line */
+#[derive(Clone, Debug)]
+struct Sign {
+    /// What put the sign there (such as `"bookmark"`), a line holds at most one sign of each group
+    group: &'static str,
+    /// Single column character drawn for the sign
+    c: char,
+    color: Color,
+}
+
+/** A row of the view: the part of a line of the body displayed on it */
+#[derive(Clone, Copy, Debug)]
+struct ViewLine {
+    /// The line it belongs to
+    y: i32,
+    /// Byte offset in the line at which the row starts
+    start: usize,
+    /// Byte offset in the line at which the row ends
+    end: usize,
+    /// Display column (within the line) at which the row starts
+    col: usize,
+}
+
+/** The rows of the view, and everything they depend on besides the lines */
+struct ViewCache {
+    version: usize,
+    width: usize,
+    wrap: bool,
+    tab_width: usize,
+    folds: Vec<(i32, i32)>,
+    rows: Rc<Vec<ViewLine>>,
+}
+
+impl ViewCache {
+    /** Whether the rows are those of the body of `buffer` at the given version */
+    fn is_for(&self, buffer: &Buffer, version: usize) -> bool {
+        return self.version == version
+            && self.width == buffer.size.0 as usize
+            && self.wrap == buffer.wrap
+            && self.tab_width == buffer.tab_width
+            && self.folds == buffer.folds;
+    }
 }
 
 impl Buffer {
-    fn new() -> Self {
+    fn new(settings: &EditorSettings) -> Self {
         Self {
             body: String::new(),
             cursor: (0, 0),
@@ -111,12 +516,39 @@ impl Buffer {
             saved: true,
             path: None,
             hold_blink: Instant::now(),
+            tab_width: settings.tab_width.max(1),
+            expand_tab: settings.expand_tab,
+            selection: None,
+            size: (0, 0),
+            desired_col: None,
+            wrap: settings.soft_wrap,
+            auto_wrap: settings.auto_wrap,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            new_step: true,
+            typing: false,
+            folds: Vec::new(),
+            signs: Vec::new(),
+            show: settings.show,
+            modified: BTreeSet::new(),
+            window: (0, 0),
+            last_click: None,
+            drag: None,
+            edits: 0,
+            last_edit: Instant::now(),
+            line_starts: OnceCell::new(),
+            view: RefCell::new(None),
+            version: 0,
+            brackets: Cell::new(None),
+            brackets_at: Cell::new(None),
+            redraw: true,
+            blink: true,
         }
     }
 
-    fn from_file(path: &String) -> Self {
+    fn from_file(path: &String, settings: &EditorSettings) -> Self {
         let body = fs::read_to_string(path);
-        Self {
+        let mut buffer = Self {
             saved: body.is_ok(),
             body: body.unwrap_or(String::new()),
             cursor: (0, 0),
@@ -124,66 +556,1297 @@ impl Buffer {
             menu: None,
             path: Some(path.clone()),
             hold_blink: Instant::now(),
+            tab_width: settings.tab_width.max(1),
+            expand_tab: settings.expand_tab,
+            selection: None,
+            size: (0, 0),
+            desired_col: None,
+            wrap: settings.soft_wrap,
+            auto_wrap: settings.auto_wrap,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            new_step: true,
+            typing: false,
+            folds: Vec::new(),
+            signs: Vec::new(),
+            show: settings.show,
+            modified: BTreeSet::new(),
+            window: (0, 0),
+            last_click: None,
+            drag: None,
+            edits: 0,
+            last_edit: Instant::now(),
+            line_starts: OnceCell::new(),
+            view: RefCell::new(None),
+            version: 0,
+            brackets: Cell::new(None),
+            brackets_at: Cell::new(None),
+            redraw: true,
+            blink: true,
+        };
+        if buffer.extension().is_some_and(|ext| settings.soft_wrap_filetypes.contains(&ext)) {
+            buffer.wrap = true;
+        }
+        if settings.detect_indent {
+            buffer.detect_indent();
         }
+        buffer.menu = buffer.recovery_menu();
+        return buffer;
     }
 
-    fn cur(&self, c: (i32, i32)) -> usize {
-        if self.body.len() == 0 {
-            return 0
+    /** Guesses the indentation style of the body, leaving the current one untouched when there is no indentation to go by */
+    fn detect_indent(&mut self) {
+        if let Some((expand_tab, width)) = detect_indent(&self.body) {
+            self.expand_tab = expand_tab;
+            if let Some(width) = width {
+                self.tab_width = width;
+            }
         }
+    }
 
-        let lines = self.body.split('\n').collect::<Vec<&str>>();
-        
-        let cy = (c.1.max(0) as usize).min(lines.len()-1);
+    /** The display column of the (byte) column `x` of the given line */
+    fn col(&self, y: i32, x: i32) -> usize {
+        let line = self.line(y.max(0) as usize);
+        display_col(line, x.max(0) as usize, self.tab_width)
+    }
+
+    /** The (byte) column of the line that's displayed closest to the display column `col` */
+    fn col_to_x(&self, y: i32, col: usize) -> i32 {
+        let line = self.line(y.max(0) as usize);
+        col_to_byte(line, col, self.tab_width) as i32
+    }
+
+    /** The text inserted for one level of indentation */
+    fn indent_unit(&self) -> String {
+        if self.expand_tab {
+            " ".repeat(self.tab_width)
+        } else {
+            "\t".to_string()
+        }
+    }
+
+    /** The extension of the file the buffer is bound to */
+    fn extension(&self) -> Option<String> {
+        self.path.as_ref()
+            .and_then(|p| Path::new(p).extension().map(|e| e.to_string_lossy().to_string()))
+    }
+
+    /** The selected text as a range of byte offsets */
+    fn selection_range(&self) -> Option<(usize, usize)> {
+        let anchor = self.cur(self.fix(self.selection?));
+        let ci = self.cur(self.fix(self.cursor));
+        if anchor == ci {
+            return None;
+        }
+        return Some((anchor.min(ci), anchor.max(ci)));
+    }
+
+    /** The first and last lines touched by the selection (or the cursor's line if there is none) */
+    fn selected_lines(&self) -> (i32, i32) {
+        let cur = self.fix(self.cursor);
+        if let Some(anchor) = self.selection {
+            let anchor = self.fix(anchor);
+            return (cur.1.min(anchor.1), cur.1.max(anchor.1));
+        }
+        return (cur.1, cur.1);
+    }
+
+    /** Removes the selected text, returns whether there was anything to remove */
+    fn delete_selection(&mut self) -> bool {
+        let range = self.selection_range();
+        self.selection = None;
+        if let Some((start, end)) = range {
+            self.replace(start..end, "");
+            self.cursor = self.ipos(start);
+            self.saved = false;
+            return true;
+        }
+        return false;
+    }
+
+    /** Indents (or dedents) all the non-blank lines between `first` and `last` by one level */
+    fn indent_lines(&mut self, first: i32, last: i32, dedent: bool) {
+        let unit = self.indent_unit();
+        let cursor = self.fix(self.cursor);
+        let selection = self.selection.map(|s| self.fix(s));
+        let (first, last) = (first.max(0) as usize, (last.max(0) as usize).min(self.line_count()-1));
+        let mut lines = (first..=last).map(|y| self.line(y).to_string()).collect::<Vec<String>>();
+        let mut deltas = vec![0i32; lines.len()];
+        for (line, delta) in lines.iter_mut().zip(deltas.iter_mut()) {
+            if dedent {
+                let n = if line.starts_with('\t') {
+                    1
+                } else {
+                    let spaces = line.chars().take_while(|c| *c == ' ').count();
+                    match spaces % self.tab_width {
+                        0 => spaces.min(self.tab_width),
+                        r => r,
+                    }
+                };
+                line.replace_range(..n, "");
+                *delta = -(n as i32);
+            } else if !line.trim().is_empty() {
+                line.insert_str(0, &unit);
+                *delta = unit.len() as i32;
+            }
+        }
+        if deltas.iter().all(|d| *d == 0) {
+            return;
+        }
+        let delta = |y: i32| (y as usize).checked_sub(first).and_then(|k| deltas.get(k)).copied().unwrap_or(0);
+        self.replace(self.lines_range(first, last), &lines.join("\n"));
+        self.cursor = ((cursor.0 + delta(cursor.1)).max(0), cursor.1);
+        self.selection = selection.map(|s| ((s.0 + delta(s.1)).max(0), s.1));
+        self.saved = false;
+    }
+
+    /** Types a character at the cursor, pairing up brackets and quotes when enabled */
+    fn type_char(&mut self, c: char, settings: &EditorSettings) {
+        let cur = self.fix(self.cursor);
+        let ci = self.cur(cur);
+        let line = self.line(cur.1 as usize);
+        let (before, after) = line.split_at(cur.0 as usize);
+        let prev = before.chars().next_back();
+        let next = after.chars().next();
+        if settings.auto_pair && next == Some(c) && (opening_bracket(c).is_some() || c == '"' || c == '\'') {
+            self.cursor = self.ipos(ci + c.len_utf8());
+            return;
+        }
+        if settings.auto_indent && opening_bracket(c).is_some() && cur.0 > 0 && before.trim().is_empty() {
+            self.indent_lines(cur.1, cur.1, true);
+        }
+        let pair = closing_pair(c).filter(|_|
+            settings.auto_pair
+            && next.is_none_or(|n| n.is_whitespace() || opening_bracket(n).is_some())
+            && (closing_bracket(c).is_some() || prev.is_none_or(|p| !p.is_alphanumeric() && p != c))
+        );
+        let ci = self.cur(self.fix(self.cursor));
+        self.replace(ci..ci, &format!("{}{}", c, pair.map(String::from).unwrap_or_default()));
+        self.cursor = self.ipos(ci + c.len_utf8());
+    }
+
+    /** Removes the character before the cursor, along with its closing pair if it directly follows */
+    fn backspace(&mut self, settings: &EditorSettings) {
+        let ci = self.cur(self.fix(self.cursor));
+        if let Some(prev) = self.body[..ci].chars().next_back() {
+            let start = ci - prev.len_utf8();
+            let mut end = ci;
+            if settings.auto_pair && closing_pair(prev).is_some() && self.body[ci..].chars().next() == closing_pair(prev) {
+                end += 1;
+            }
+            self.replace(start..end, "");
+            self.cursor = self.ipos(start);
+            self.saved = false;
+        }
+    }
+
+    /** The byte offset of the bracket matching the one at the byte offset `i` */
+    fn matching_bracket(&self, i: usize) -> Option<usize> {
+        let c = self.body.get(i..)?.chars().next()?;
+        let mut depth = 0i32;
+        if let Some(close) = closing_bracket(c) {
+            for (j, d) in self.body[i..].char_indices() {
+                if d == c {
+                    depth += 1;
+                } else if d == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(i+j);
+                    }
+                }
+            }
+        } else if let Some(open) = opening_bracket(c) {
+            for (j, d) in self.body[..=i].char_indices().rev() {
+                if d == c {
+                    depth += 1;
+                } else if d == open {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(j);
+                    }
+                }
+            }
+        }
+        return None;
+    }
+
+    /** The byte offset of the start of the word before `i` */
+    fn word_left(&self, i: usize) -> usize {
+        let mut chars = self.body[..i].char_indices().rev().skip_while(|(_, c)| c.is_whitespace()).peekable();
+        let class = match chars.peek() {
+            Some((_, c)) => char_class(*c),
+            None => return 0,
+        };
+        let mut start = i;
+        for (j, c) in chars {
+            if char_class(c) != class {
+                break;
+            }
+            start = j;
+        }
+        return start;
+    }
+
+    /** The byte offset of the end of the word after `i` */
+    fn word_right(&self, i: usize) -> usize {
+        let mut chars = self.body[i..].char_indices().skip_while(|(_, c)| c.is_whitespace()).peekable();
+        let class = match chars.peek() {
+            Some((_, c)) => char_class(*c),
+            None => return self.body.len(),
+        };
+        for (j, c) in chars {
+            if char_class(c) != class {
+                return i+j;
+            }
+        }
+        return self.body.len();
+    }
+
+    /** The byte range of the word (or run of characters of the same kind) at `i` */
+    fn word_at(&self, i: usize) -> (usize, usize) {
+        let class = match self.body[i..].chars().next().filter(|c| *c != '\n') {
+            Some(c) => char_class(c),
+            None => return (i, i),
+        };
+        let start = self.body[..i]
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| *c != '\n' && char_class(*c) == class)
+            .last()
+            .map(|(j, _)| j)
+            .unwrap_or(i);
+        let end = self.body[i..]
+            .char_indices()
+            .find(|(_, c)| *c == '\n' || char_class(*c) != class)
+            .map(|(j, _)| i+j)
+            .unwrap_or(self.body.len());
+        return (start, end);
+    }
+
+    /** The bracket under (or right before) the cursor and the one matching it */
+    fn bracket_pair(&self) -> Option<(usize, usize)> {
+        if self.brackets_at.get() == Some((self.version, self.cursor)) {
+            return self.brackets.get();
+        }
+        let ci = self.cur(self.fix(self.cursor));
+        let pair = match self.matching_bracket(ci) {
+            Some(m) => Some((ci, m)),
+            None => self.body[..ci].chars().next_back()
+                .and_then(|prev| self.matching_bracket(ci - prev.len_utf8()).map(|m| (ci - prev.len_utf8(), m))),
+        };
+        self.brackets.set(pair);
+        self.brackets_at.set(Some((self.version, self.cursor)));
+        return pair;
+    }
+
+    /** Makes the edit about to be made a step of its own in the undo history */
+    fn checkpoint(&mut self) {
+        self.new_step = true;
+    }
+
+    /** Puts a change on the undo stack, in the last step unless a new one was asked for, merging it with the previous change when they touch */
+    fn record(&mut self, change: Change) {
+        self.redo.clear();
+        if !self.new_step {
+            if let Some(step) = self.undo.last_mut() {
+                match step.last_mut() {
+                    Some(last) if last.end == change.start => {
+                        last.end = change.end;
+                        last.text += &change.text;
+                    }
+                    _ => step.push(change),
+                }
+                return;
+            }
+        }
+        self.new_step = false;
+        self.undo.push(vec![change]);
+        if self.undo.len() > UNDO_LIMIT {
+            self.undo.remove(0);
+        }
+    }
+
+    /** Goes back to the state before the last step */
+    fn undo(&mut self) {
+        if let Some(step) = self.undo.pop() {
+            let cursor = step[0].cursor;
+            let step = self.revert(step);
+            self.redo.push(step);
+            self.cursor = cursor;
+            self.selection = None;
+            self.saved = false;
+        }
+        self.new_step = true;
+    }
+
+    /** Re-applies the last undone step */
+    fn redo(&mut self) {
+        if let Some(step) = self.redo.pop() {
+            let cursor = step[0].cursor;
+            let step = self.revert(step);
+            self.undo.push(step);
+            self.cursor = cursor;
+            self.selection = None;
+            self.saved = false;
+        }
+        self.new_step = true;
+    }
+
+    /** Reverts the changes of a step in the body (last one first), returns the step that would redo it */
+    fn revert(&mut self, step: Vec<Change>) -> Vec<Change> {
+        return step.into_iter().rev().map(|change| {
+            let text = self.splice(change.start .. change.end, &change.text);
+            Change { start: change.start, end: change.start + change.text.len(), text, cursor: self.cursor }
+        }).collect();
+    }
+
+    /** The first and last lines of the paragraph around the cursor */
+    fn paragraph(&self) -> (i32, i32) {
+        let y = self.fix(self.cursor).1 as usize;
+        let marker = |line: &str| line_prefix(line).trim().to_string();
+        let blank = |line: &str| line[line_prefix(line).len()..].trim().is_empty();
+        if blank(self.line(y)) {
+            return (y as i32, y as i32);
+        }
+        let (mut first, mut last) = (y, y);
+        while first > 0 && !blank(self.line(first-1)) && marker(self.line(first-1)) == marker(self.line(y)) {
+            first -= 1;
+        }
+        while last+1 < self.line_count() && !blank(self.line(last+1)) && marker(self.line(last+1)) == marker(self.line(y)) {
+            last += 1;
+        }
+        return (first as i32, last as i32);
+    }
+
+    /** Refills the lines between `first` and `last` up to `width` columns, keeping the prefix of the first one */
+    fn reflow(&mut self, first: i32, last: i32, width: usize) {
+        let (first, last) = (first.max(0) as usize, (last.max(0) as usize).min(self.line_count()-1));
+        let prefix = line_prefix(self.line(first)).to_string();
+        let words = (first..=last)
+            .map(|y| self.line(y))
+            .flat_map(|l| l[line_prefix(l).len()..].split_whitespace().map(|w| w.to_string()).collect::<Vec<String>>())
+            .collect::<Vec<String>>();
+        if words.is_empty() {
+            return;
+        }
+        let width = width.max(display_col(&prefix, prefix.len(), self.tab_width) + 1);
+        let mut filled: Vec<String> = Vec::new();
+        let mut line = prefix.clone();
+        for word in words {
+            if line.len() > prefix.len() && display_col(&line, line.len(), self.tab_width) + 1 + word.chars().count() > width {
+                filled.push(std::mem::replace(&mut line, prefix.clone()));
+            }
+            if line.len() > prefix.len() || !(prefix.is_empty() || prefix.ends_with(char::is_whitespace)) {
+                line.push(' ');
+            }
+            line += &word;
+        }
+        filled.push(line);
+        let end = (first + filled.len() - 1) as i32;
+        let end_x = filled.last().unwrap().len() as i32;
+        self.replace(self.lines_range(first, last), &filled.join("\n"));
+        self.cursor = (end_x, end);
+        self.saved = false;
+    }
+
+    /** Breaks the cursor's line at the last space before `width` if it is too long, keeping its prefix */
+    fn break_line(&mut self, width: usize) {
+        let cur = self.fix(self.cursor);
+        let line = self.line(cur.1 as usize);
+        if display_col(line, line.len(), self.tab_width) <= width {
+            return;
+        }
+        let prefix = line_prefix(line).to_string();
+        let mut brk = None;
+        for (i, c) in line.char_indices().skip(prefix.chars().count()) {
+            if display_col(line, i, self.tab_width) > width {
+                break;
+            }
+            if c.is_whitespace() {
+                brk = Some(i);
+            }
+        }
+        let Some(end) = brk else {
+            return;
+        };
+        let start = line[..end].trim_end().len();
+        let end = end + line[end..].chars().next().map(|c| c.len_utf8()).unwrap_or(0);
+        if start <= prefix.len() {
+            return;
+        }
+        let ls = self.cur((0, cur.1));
+        self.replace(ls+start..ls+end, &format!("\n{}", prefix.trim_end()));
+        if cur.0 as usize >= end {
+            self.cursor = (cur.0 - end as i32 + prefix.trim_end().len() as i32, cur.1+1);
+        }
+    }
+
+    /** Runs an action bound to some keys */
+    fn run(&mut self, env: &mut Env, action: Action) {
+        if !action.is_vertical() {
+            self.desired_col = None;
+        }
+        self.typing = false;
+        if action.is_edit() {
+            self.checkpoint();
+        }
+        if let Some(motion) = action.selecting() {
+            if self.selection.is_none() {
+                self.selection = Some(self.fix(self.cursor));
+            }
+            self.motion(env, motion);
+            return;
+        }
+        if action.is_motion() {
+            self.selection = None;
+            self.motion(env, action);
+            return;
+        }
+        match action {
+            Action::Menu => {
+                self.menu = Some(BufferMenuState::Open(String::new()));
+            }
+            Action::Tab => {
+                if self.selection.is_some() {
+                    let (first, last) = self.selected_lines();
+                    self.indent_lines(first, last, false);
+                } else {
+                    let ci = self.cur(self.fix(self.cursor));
+                    if self.expand_tab {
+                        let cur = self.fix(self.cursor);
+                        let col = self.col(cur.1, cur.0);
+                        let n = self.tab_width - col % self.tab_width;
+                        self.replace(ci..ci, &" ".repeat(n));
+                        self.cursor = self.ipos(ci+n);
+                    } else {
+                        self.replace(ci..ci, "\t");
+                        self.cursor = self.ipos(ci+1);
+                    }
+                    self.saved = false;
+                }
+            }
+            Action::Dedent => {
+                let (first, last) = self.selected_lines();
+                self.indent_lines(first, last, true);
+            }
+            Action::Newline => {
+                self.delete_selection();
+                self.newline(&env.settings.editor);
+                self.saved = false;
+            }
+            Action::Backspace => {
+                if !self.delete_selection() {
+                    self.backspace(&env.settings.editor);
+                }
+            }
+            Action::Delete => {
+                if !self.delete_selection() {
+                    let ci = self.cur(self.fix(self.cursor));
+                    if let Some(c) = self.body[ci..].chars().next() {
+                        self.replace(ci..ci+c.len_utf8(), "");
+                        self.saved = false;
+                    }
+                }
+            }
+            Action::DeleteWordBack => {
+                if !self.delete_selection() {
+                    let ci = self.cur(self.fix(self.cursor));
+                    let start = self.word_left(ci);
+                    if start < ci {
+                        self.replace(start..ci, "");
+                        self.cursor = self.ipos(start);
+                        self.saved = false;
+                    }
+                }
+            }
+            Action::DeleteWordForward => {
+                if !self.delete_selection() {
+                    let ci = self.cur(self.fix(self.cursor));
+                    let end = self.word_right(ci);
+                    if end > ci {
+                        self.replace(ci..end, "");
+                        self.cursor = self.ipos(ci);
+                        self.saved = false;
+                    }
+                }
+            }
+            Action::GotoLine => {
+                self.selection = None;
+                self.menu = Some(BufferMenuState::Goto(String::new()));
+            }
+            Action::ScrollUp => {
+                if self.scroll.1 > 0 {
+                    self.scroll.1 -= 1;
+                }
+            }
+            Action::ScrollDown => {
+                if (self.scroll.1 as usize) +1 < self.view_lines().len() {
+                    self.scroll.1 += 1;
+                }
+            }
+            Action::ScrollLeft => {
+                if self.scroll.0 > 0 {
+                    self.scroll.0 -= 1;
+                }
+            }
+            Action::ScrollRight => {
+                let maxlen = (0..self.line_count()).map(|y| self.line(y).len()).max().unwrap();
+                if !self.wrap && (self.scroll.0 as usize) +1 < maxlen {
+                    self.scroll.0 += 1;
+                }
+            }
+            Action::Undo => {
+                self.undo();
+            }
+            Action::Redo => {
+                self.redo();
+            }
+            Action::Reflow => {
+                let (first, last) = if self.selection.is_some() {
+                    self.selected_lines()
+                } else {
+                    self.paragraph()
+                };
+                self.selection = None;
+                self.reflow(first, last, env.settings.editor.text_width);
+            }
+            Action::ToggleAutoWrap => {
+                self.auto_wrap = !self.auto_wrap;
+            }
+            Action::ToggleWrap => {
+                self.wrap = !self.wrap;
+                self.scroll_to_cursor(&env.settings.editor);
+            }
+            Action::ToggleFold => {
+                let y = self.fix(self.cursor).1;
+                if self.folded(y).is_some() {
+                    self.folds.retain(|f| f.0 != y);
+                } else if let Some(fold) = self.fold_range(y).or_else(|| self.enclosing_fold(y)) {
+                    self.folds.push(fold);
+                    self.cursor = (self.cursor.0, fold.0);
+                    self.selection = None;
+                }
+            }
+            Action::FoldAll => {
+                let lines = self.line_count() as i32;
+                self.folds = (0..lines).filter_map(|y| self.fold_range(y)).collect();
+                let y = self.fix(self.cursor).1;
+                if let Some((a, _)) = self.hidden(y) {
+                    self.cursor = (0, a);
+                }
+                self.selection = None;
+            }
+            Action::UnfoldAll => {
+                self.folds.clear();
+            }
+            Action::ToggleSpaces => {
+                self.show.spaces = !self.show.spaces;
+            }
+            Action::ToggleTabs => {
+                self.show.tabs = !self.show.tabs;
+            }
+            Action::ToggleTrailing => {
+                self.show.trailing = !self.show.trailing;
+            }
+            Action::ToggleIndentGuides => {
+                self.show.indent_guides = !self.show.indent_guides;
+            }
+            Action::ToggleEol => {
+                self.show.eol = !self.show.eol;
+            }
+            Action::ToggleRulers => {
+                self.show.rulers = !self.show.rulers;
+            }
+            Action::ToggleBookmark => {
+                let y = self.fix(self.cursor).1;
+                if self.signs.iter().any(|(l, s)| *l == y && s.group == "bookmark") {
+                    self.remove_sign(y, "bookmark");
+                } else {
+                    self.set_sign(y, Sign { group: "bookmark", c: '●', color: YELLOW });
+                }
+            }
+            Action::Suspend => {
+                terminal::suspend();
+            }
+            _ => {}
+        }
+    }
+
+    /** The last line of the fold starting at the line `y`, if it is folded */
+    fn folded(&self, y: i32) -> Option<i32> {
+        return self.folds.iter().filter(|f| f.0 == y).map(|f| f.1).max();
+    }
 
-        let mut j = 0usize;
+    /** The outermost fold hiding the line `y`, if any */
+    fn hidden(&self, y: i32) -> Option<(i32, i32)> {
+        let a = self.folds.iter().filter(|f| f.0 < y && y <= f.1).map(|f| f.0).min()?;
+        return Some((a, self.folded(a).unwrap()));
+    }
 
-        for y in 0..lines.len() {
-            let l = lines[y].len() + 1;
-            if cy == y {
-                let cx = (c.0.max(0) as usize).min(l-1);
-                return j + cx;
+    /** The lines that can be folded under the line `y`: the ones following it that are indented deeper */
+    fn fold_range(&self, y: i32) -> Option<(i32, i32)> {
+        if y < 0 || y as usize >= self.line_count() {
+            return None;
+        }
+        let indent = |line: &str| display_col(line, leading_whitespaces(line), self.tab_width);
+        let header = self.line(y as usize);
+        if header.trim().is_empty() {
+            return None;
+        }
+        let mut last = y;
+        for k in (y as usize + 1)..self.line_count() {
+            let line = self.line(k);
+            if line.trim().is_empty() {
+                continue;
             }
-            j += l;
+            if indent(line) <= indent(header) {
+                break;
+            }
+            last = k as i32;
+        }
+        return (last > y).then_some((y, last));
+    }
+
+    /** The fold of the closest line above `y` whose block contains it */
+    fn enclosing_fold(&self, y: i32) -> Option<(i32, i32)> {
+        return (0..y).rev().filter_map(|k| self.fold_range(k)).find(|f| f.1 >= y);
+    }
+
+    /** Unfolds whatever hides the line `y` */
+    fn reveal(&mut self, y: i32) {
+        self.folds.retain(|f| !(f.0 < y && y <= f.1));
+    }
+
+    /** Puts a sign next to the line `y`, replacing the one of the same group already there */
+    fn set_sign(&mut self, y: i32, sign: Sign) {
+        self.remove_sign(y, sign.group);
+        self.signs.push((y, sign));
+    }
+
+    /** Removes the sign of the given group next to the line `y` */
+    fn remove_sign(&mut self, y: i32, group: &str) {
+        self.signs.retain(|(l, s)| *l != y || s.group != group);
+    }
+
+    /** Removes all the signs of the given group */
+    fn clear_signs(&mut self, group: &str) {
+        self.signs.retain(|(_, s)| s.group != group);
+    }
+
+    /** The sign shown next to the line `y`, the one put last wins */
+    fn sign(&self, y: i32) -> Option<&Sign> {
+        return self.signs.iter().rev().find(|(l, _)| *l == y).map(|(_, s)| s);
+    }
+
+    /** The widths of the sign column and of the line numbers in the gutter */
+    fn gutter(&self, settings: &EditorSettings) -> (u32, u32) {
+        let signs = match settings.sign_column {
+            SignColumn::Always => 1,
+            SignColumn::Auto if !self.signs.is_empty() => 1,
+            _ => 0,
+        };
+        let numbers = match settings.line_numbers {
+            LineNumbers::None => 0,
+            _ => self.line_count().to_string().len().max(3) as u32 + 1,
+        };
+        return (signs, numbers);
+    }
+
+    /**
+     * The column the text starts at (after the gutter and its separator) and the size of the text area,
+     * in a window of the given size, if there is room for it
+     */
+    fn layout(&self, settings: &EditorSettings, w: u32, h: u32) -> Option<(u32, u32, u32)> {
+        let (sw, nw) = self.gutter(settings);
+        let gx = sw + nw + 1;
+        let right = settings.scrollbar as u32 + settings.minimap_width as u32;
+        if w < gx + right + 5 || h < 3 {
+            return None;
+        }
+        return Some((gx, w - gx - right, h - 2));
+    }
+
+    /** The byte offset at which each line of the body starts, only worked out again after the body changed */
+    fn line_starts(&self) -> &[usize] {
+        return self.line_starts.get_or_init(|| {
+            std::iter::once(0).chain(self.body.match_indices('\n').map(|(i, _)| i + 1)).collect()
+        });
+    }
+
+    /** The number of lines of the body */
+    fn line_count(&self) -> usize {
+        return self.line_starts().len();
+    }
+
+    /** The line `y` of the body (without its line feed), empty past the last one */
+    fn line(&self, y: usize) -> &str {
+        let starts = self.line_starts();
+        return match starts.get(y) {
+            Some(&start) => &self.body[start .. starts.get(y+1).map(|s| s - 1).unwrap_or(self.body.len())],
+            None => "",
+        };
+    }
+
+    /** The byte range of the body from the start of the line `first` to the end of the line `last` */
+    fn lines_range(&self, first: usize, last: usize) -> Range<usize> {
+        let starts = self.line_starts();
+        return starts[first] .. starts[last] + self.line(last).len();
+    }
+
+    /** Replaces a byte range of the body as an edit that can be undone, which every edit goes through */
+    fn replace(&mut self, range: Range<usize>, text: &str) {
+        if range.is_empty() && text.is_empty() {
+            return;
+        }
+        let start = range.start;
+        let removed = self.splice(range, text);
+        self.record(Change { start, end: start + text.len(), text: removed, cursor: self.cursor });
+    }
+
+    /** Replaces a byte range of the body, keeping the lines and what hangs on them up to date, returns the text it replaced */
+    fn splice(&mut self, range: Range<usize>, text: &str) -> String {
+        let (first, last) = (self.ipos(range.start).1 as usize, self.ipos(range.end).1 as usize);
+        let lines = self.lines_range(first, last);
+        let old = self.body[lines.clone()].to_string();
+        let removed = self.body[range.clone()].to_string();
+        // The rows of the view can be patched up as long as no fold reaches the edited lines or the ones after them
+        let patch = self.folds.iter().all(|f| (f.1 as usize) < first)
+            && self.view.borrow().as_ref().is_some_and(|cache| cache.is_for(self, self.version));
+        self.body.replace_range(range.clone(), text);
+        if let Some(starts) = self.line_starts.get_mut() {
+            // The lines the edit went through get the starts of its own lines, the ones after it move along
+            let added = text.match_indices('\n').map(|(i, _)| range.start + i + 1).collect::<Vec<usize>>();
+            let after = first + 1 + added.len();
+            starts.splice(first+1 ..= last, added);
+            for start in &mut starts[after..] {
+                *start = *start + text.len() - range.len();
+            }
+        }
+        self.version += 1;
+        self.remap_lines(first as i32, &old, lines.start .. lines.end + text.len() - range.len());
+        if patch {
+            let delta = text.matches('\n').count() as i32 - removed.matches('\n').count() as i32;
+            let mut rows = Vec::new();
+            for y in first ..= (last as i32 + delta) as usize {
+                self.push_rows(y, &mut rows);
+            }
+            let added = rows.len();
+            if let Some(cache) = self.view.get_mut() {
+                let view = Rc::make_mut(&mut cache.rows);
+                let a = view.partition_point(|v| (v.y as usize) < first);
+                let b = view.partition_point(|v| v.y as usize <= last);
+                view.splice(a..b, rows);
+                for v in &mut view[a+added..] {
+                    v.y += delta;
+                }
+                cache.version = self.version;
+            }
+        }
+        return removed;
+    }
+
+    /** Whether the blinking cursor is currently on */
+    fn cursor_on(&self) -> bool {
+        return Instant::now().saturating_duration_since(self.hold_blink).as_millis() % (2*BLINK_RATE.as_millis()) < BLINK_RATE.as_millis();
+    }
+
+    /**
+     * Moves the folds, signs and modified lines along with their lines after an edit turned the lines `old` (starting at the line `first`)
+     * into the byte range `new` of the body, dropping the folds the edit went through
+     */
+    fn remap_lines(&mut self, first: i32, old: &str, new: Range<usize>) {
+        let old = old.split('\n').collect::<Vec<&str>>();
+        let new = self.body[new].split('\n').collect::<Vec<&str>>();
+        let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+        let limit = old.len().min(new.len()) - prefix;
+        let suffix = old.iter().rev().zip(new.iter().rev()).take(limit).take_while(|(a, b)| a == b).count();
+        let (start, end) = (first + prefix as i32, first + (old.len() - suffix) as i32);
+        let delta = new.len() as i32 - old.len() as i32;
+        self.folds = self.folds
+            .iter()
+            .filter_map(|&(a, b)| {
+                if b < start {
+                    Some((a, b))
+                } else if a >= end {
+                    Some((a + delta, b + delta))
+                } else if a == start && end == start + 1 && delta == 0 {
+                    // Only the first line changed
+                    Some((a, b))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.signs.retain_mut(|(y, _)| {
+            if *y >= end {
+                *y += delta;
+                return true;
+            }
+            // Signs on the edited lines stay where they are, as long as the line is still there
+            return *y < end + delta;
+        });
+        self.modified = self.modified
+            .iter()
+            .filter_map(|&y| if y < start { Some(y) } else if y >= end { Some(y + delta) } else { None })
+            .chain(start..(end + delta).max(start + 1).min(first + new.len() as i32))
+            .collect();
+    }
+
+    /** The rows of the view, with lines soft wrapped to its width if enabled and folded lines left out, only worked out again when one of these changed */
+    fn view_lines(&self) -> Rc<Vec<ViewLine>> {
+        if let Some(cache) = &*self.view.borrow() {
+            if cache.is_for(self, self.version) {
+                return cache.rows.clone();
+            }
+        }
+        let mut view = Vec::new();
+        let mut skip = -1;
+        for y in 0..self.line_count() {
+            if y as i32 <= skip {
+                continue;
+            }
+            if let Some(end) = self.folded(y as i32) {
+                skip = end;
+            }
+            self.push_rows(y, &mut view);
+        }
+        let width = self.size.0 as usize;
+        let rows = Rc::new(view);
+        *self.view.borrow_mut() = Some(ViewCache {
+            version: self.version,
+            width,
+            wrap: self.wrap,
+            tab_width: self.tab_width,
+            folds: self.folds.clone(),
+            rows: rows.clone(),
+        });
+        return rows;
+    }
+
+    /** Adds the rows the line `y` takes up in the view */
+    fn push_rows(&self, y: usize, view: &mut Vec<ViewLine>) {
+        let line = self.line(y);
+        let width = self.size.0 as usize;
+        if self.wrap && width > 0 {
+            let rows = wrap_line(line, width, self.tab_width);
+            for (k, (start, col)) in rows.iter().enumerate() {
+                let end = rows.get(k+1).map(|r| r.0).unwrap_or(line.len());
+                view.push(ViewLine { y: y as i32, start: *start, end, col: *col });
+            }
+        } else {
+            view.push(ViewLine { y: y as i32, start: 0, end: line.len(), col: 0 });
+        }
+    }
+
+    /** The row of the view and the column within it at which the (byte) position `c` is displayed */
+    fn view_pos(&self, view: &[ViewLine], c: (i32, i32)) -> (i32, i32) {
+        let i = view
+            .partition_point(|v| v.y < c.1 || (v.y == c.1 && v.start as i32 <= c.0))
+            .saturating_sub(1);
+        let v = view[i];
+        if v.y != c.1 {
+            return (i as i32, 0);
+        }
+        return (i as i32, self.col(c.1, c.0) as i32 - v.col as i32);
+    }
+
+    /** The (byte) column of the line displayed closest to the column `col` of the row `v` */
+    fn view_x(&self, v: ViewLine, col: usize) -> i32 {
+        let line = self.line(v.y as usize);
+        let x = col_to_byte(line, v.col + col, self.tab_width);
+        if x >= v.end && v.end < line.len() {
+            // Stay on the row rather than at the start of the next one
+            return line[..v.end].char_indices().next_back().map(|(i, _)| i).unwrap_or(v.start) as i32;
+        }
+        return x as i32;
+    }
+
+    /** Moves the cursor `delta` rows up or down the view, staying in the same column */
+    fn move_rows(&mut self, delta: i32, clamp: bool) {
+        let view = self.view_lines();
+        let (row, col) = self.view_pos(&view, self.fix(self.cursor));
+        let mut target = row + delta;
+        if clamp {
+            target = target.clamp(0, view.len() as i32 - 1);
+        }
+        if target < 0 {
+            self.cursor = (0,0);
+            self.desired_col = None;
+        } else if target as usize >= view.len() {
+            self.cursor = self.ipos(self.body.len());
+            self.desired_col = None;
+        } else {
+            let col = self.desired_col.unwrap_or(col.max(0) as usize);
+            let v = view[target as usize];
+            self.cursor = (self.view_x(v, col), v.y);
+            self.desired_col = Some(col);
+        }
+    }
+
+    /** Scrolls the view just enough to keep the cursor away from its edges */
+    fn scroll_to_cursor(&mut self, settings: &EditorSettings) {
+        let (tw, th) = (self.size.0 as i32, self.size.1 as i32);
+        if tw <= 0 || th <= 0 {
+            return;
+        }
+        let view = self.view_lines();
+        let (row, col) = self.view_pos(&view, self.fix(self.cursor));
+        let rows = view.len() as i32;
+        let my = (settings.scroll_off as i32).min((th-1)/2);
+        let mx = (settings.side_scroll_off as i32).min((tw-1)/2);
+        if row - my < self.scroll.1 {
+            self.scroll.1 = (row - my).max(0);
+        } else if row + my >= self.scroll.1 + th {
+            self.scroll.1 = (row + my - th + 1).min((rows - th).max(row - th + 1));
+        }
+        if self.wrap {
+            self.scroll.0 = 0;
+        } else if col - mx < self.scroll.0 {
+            self.scroll.0 = (col - mx).max(0);
+        } else if col + mx >= self.scroll.0 + tw {
+            self.scroll.0 = col + mx - tw + 1;
+        }
+    }
+
+    /** The position in the body displayed at the (0 based) screen coordinates, clamped to the text area */
+    fn screen_pos(&self, settings: &EditorSettings, x: u32, y: u32) -> (i32, i32) {
+        let (sw, nw) = self.gutter(settings);
+        let view = self.view_lines();
+        let row = (y as i32 - 1).clamp(0, self.size.1 as i32 - 1) + self.scroll.1;
+        if row as usize >= view.len() {
+            return self.ipos(self.body.len());
+        }
+        let col = (x as i32 - (sw + nw + 1) as i32).clamp(0, self.size.0 as i32 - 1) + self.scroll.0;
+        let v = view[row as usize];
+        return (self.view_x(v, col as usize), v.y);
+    }
+
+    /** Scrolls the view to the part of the body standing at the height `y` of the scrollbar */
+    fn scroll_to_bar(&mut self, y: u32) {
+        let rows = self.view_lines().len() as i32;
+        let th = self.size.1.max(1) as i32;
+        let target = (y as i32 - 1) * rows / th - th / 2;
+        self.scroll.1 = target.clamp(0, (rows - th).max(0));
+    }
+
+    /** Handles the mouse: clicks and drags in the text, on the scrollbar, the header and the status bar, and the wheel */
+    fn mouse(&mut self, env: &mut Env, ev: MouseEvent) {
+        let (w, h) = self.window;
+        // Nothing can be clicked before the first render, or while the window is too small to show the text
+        if self.layout(&env.settings.editor, w, h).is_none() {
+            return;
+        }
+        let text_end = w.saturating_sub(env.settings.editor.scrollbar as u32 + env.settings.editor.minimap_width as u32);
+        match ev {
+            MouseEvent::Press(MouseButton::Left, x, y) => {
+                let (x, y) = ((x as u32).saturating_sub(1), (y as u32).saturating_sub(1));
+                self.drag = None;
+                if y == 0 {
+                    self.menu = Some(BufferMenuState::Open(String::new()));
+                } else if y + 1 == h {
+                    // While a menu is open, the status bar belongs to it
+                    if self.menu.is_none() {
+                        let cur = self.fix(self.cursor);
+                        let pos = format!("{}:{}", cur.1+1, self.col(cur.1, cur.0)+1);
+                        self.menu = Some(if x + 1 + pos.len() as u32 >= w {
+                            BufferMenuState::Goto(String::new())
+                        } else {
+                            BufferMenuState::Open(String::new())
+                        });
+                    }
+                } else if x >= text_end {
+                    self.drag = Some(Drag::Scrollbar);
+                    self.scroll_to_bar(y);
+                } else {
+                    self.menu = None;
+                    let pos = self.screen_pos(&env.settings.editor, x, y);
+                    let double = self.last_click.is_some_and(|(t, p)| p == pos && t.elapsed() < DOUBLE_CLICK);
+                    self.desired_col = None;
+                    self.typing = false;
+                    if double {
+                        let (start, end) = self.word_at(self.cur(pos));
+                        self.selection = Some(self.ipos(start));
+                        self.cursor = self.ipos(end);
+                        self.last_click = None;
+                    } else {
+                        self.selection = None;
+                        self.cursor = pos;
+                        self.last_click = Some((Instant::now(), pos));
+                        self.drag = Some(Drag::Text);
+                    }
+                }
+            }
+            MouseEvent::Hold(x, y) => {
+                let (x, y) = ((x as u32).saturating_sub(1), (y as u32).saturating_sub(1));
+                match self.drag {
+                    Some(Drag::Text) => {
+                        let pos = self.screen_pos(&env.settings.editor, x, y);
+                        if pos != self.cursor && self.selection.is_none() {
+                            self.selection = Some(self.cursor);
+                        }
+                        self.cursor = pos;
+                    }
+                    Some(Drag::Scrollbar) => {
+                        self.scroll_to_bar(y);
+                    }
+                    None => {}
+                }
+            }
+            MouseEvent::Release(_, _) => {
+                self.drag = None;
+            }
+            MouseEvent::Press(MouseButton::WheelUp, _, _) => {
+                for _ in 0..WHEEL_ROWS {
+                    self.run(env, Action::ScrollUp);
+                }
+            }
+            MouseEvent::Press(MouseButton::WheelDown, _, _) => {
+                for _ in 0..WHEEL_ROWS {
+                    self.run(env, Action::ScrollDown);
+                }
+            }
+            MouseEvent::Press(MouseButton::WheelLeft, _, _) => {
+                self.run(env, Action::ScrollLeft);
+            }
+            MouseEvent::Press(MouseButton::WheelRight, _, _) => {
+                self.run(env, Action::ScrollRight);
+            }
+            _ => {}
+        }
+    }
+
+    /** Moves the cursor to a `line[:col]` location (counting from 1), returns whether it could be understood */
+    fn goto(&mut self, location: &str) -> bool {
+        let mut parts = location.trim().splitn(2, ':');
+        let line = parts.next().and_then(|l| l.trim().parse::<i32>().ok());
+        let col = match parts.next() {
+            Some(c) => match c.trim().parse::<usize>() {
+                Ok(c) => Some(c),
+                Err(_) => return false,
+            },
+            None => None,
+        };
+        if let Some(line) = line {
+            let last = self.line_count() as i32 - 1;
+            let y = (line-1).clamp(0, last);
+            self.cursor = (self.col_to_x(y, col.unwrap_or(1).max(1)-1), y);
+            self.selection = None;
+            self.desired_col = None;
+            return true;
+        }
+        return false;
+    }
+
+    /** Moves the cursor according to a motion action */
+    fn motion(&mut self, env: &Env, action: Action) {
+        let cur = self.fix(self.cursor);
+        match action {
+            Action::MoveUp => {
+                self.move_rows(-1, false);
+            }
+            Action::MoveDown => {
+                self.move_rows(1, false);
+            }
+            Action::MoveLeft => {
+                let ci = self.cur(cur);
+                if let Some(c) = self.body[..ci].chars().next_back() {
+                    self.cursor = self.ipos(ci-c.len_utf8());
+                }
+            }
+            Action::MoveRight => {
+                let ci = self.cur(cur);
+                if let Some(c) = self.body[ci..].chars().next() {
+                    self.cursor = self.ipos(ci+c.len_utf8());
+                }
+            }
+            Action::LineEnd => {
+                self.cursor.0 = self.line(cur.1 as usize).len() as i32;
+            }
+            Action::LineStart => {
+                if env.settings.controls.smart_home {
+                    let line = self.line(cur.1 as usize);
+                    let i = leading_whitespaces(line) as i32;
+
+                    self.cursor.0 = if cur.0 == i { 0 } else { i };
+                } else {
+                    self.cursor.0 = 0;
+                }
+            }
+            Action::MatchingBracket => {
+                if let Some((_, m)) = self.bracket_pair() {
+                    self.cursor = self.ipos(m);
+                }
+            }
+            Action::WordLeft => {
+                self.cursor = self.ipos(self.word_left(self.cur(cur)));
+            }
+            Action::WordRight => {
+                self.cursor = self.ipos(self.word_right(self.cur(cur)));
+            }
+            Action::PageUp | Action::PageDown => {
+                let page = self.size.1.max(1) as i32;
+                let page = if action == Action::PageUp { -page } else { page };
+                self.move_rows(page, true);
+                let last = self.view_lines().len() as i32 - 1;
+                self.scroll.1 = (self.scroll.1 + page).clamp(0, last);
+            }
+            Action::DocumentStart => {
+                self.cursor = (0, 0);
+            }
+            Action::DocumentEnd => {
+                self.cursor = self.ipos(self.body.len());
+            }
+            Action::NextBookmark => {
+                let mut marks = self.signs.iter().filter(|(_, s)| s.group == "bookmark").map(|(l, _)| *l).collect::<Vec<i32>>();
+                marks.sort();
+                if let Some(y) = marks.iter().find(|l| **l > cur.1).or(marks.first()) {
+                    self.cursor = (0, *y);
+                }
+            }
+            _ => {}
+        }
+        // Step over folded lines rather than into them
+        if let Some((a, b)) = self.hidden(self.cursor.1) {
+            let lines = self.line_count() as i32;
+            let header = (self.line(a as usize).len() as i32, a);
+            self.cursor = match action {
+                Action::MoveLeft | Action::WordLeft => header,
+                Action::MoveRight | Action::WordRight if b+1 < lines => (0, b+1),
+                Action::MoveRight | Action::WordRight => header,
+                _ => self.cursor,
+            };
+        }
+    }
+
+    /**
+     * The menu offering to recover what a crash left of the file (its recovery copy, or else a swap file of a dead editor),
+     * or warning that another editor has it open. Leftovers that match the file are removed.
+     */
+    fn recovery_menu(&self) -> Option<BufferMenuState> {
+        let path = self.path.as_deref()?;
+        if let Some(text) = recovery::load(path) {
+            if text != self.body {
+                return Some(BufferMenuState::Recover(text));
+            }
+            recovery::discard(path);
+        }
+        let (pid, text) = recovery::read_swap(path)?;
+        if pid == std::process::id() {
+            return None;
+        }
+        if recovery::alive(pid) {
+            return Some(BufferMenuState::Open(format!("Also being edited by process {}", pid)));
+        }
+        if text != self.body {
+            return Some(BufferMenuState::Recover(text));
+        }
+        recovery::remove_swap(path);
+        return None;
+    }
+
+    /** Writes the swap file of the buffer */
+    fn write_swap(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = recovery::write_swap(path, &self.body);
+        }
+        self.edits = 0;
+    }
+
+    /** Replaces the body with its recovery copy, marking the lines that differ from the file if `diff` */
+    fn recover(&mut self, text: String, diff: bool) {
+        self.checkpoint();
+        self.clear_signs("recovered");
+        if diff {
+            for y in recovery::changed_lines(&self.body, &text) {
+                self.set_sign(y, Sign { group: "recovered", c: '▎', color: YELLOW });
+            }
+        }
+        self.replace(0..self.body.len(), &text);
+        self.cursor = self.fix(self.cursor);
+        self.selection = None;
+        self.saved = false;
+        if let Some(path) = &self.path {
+            recovery::discard(path);
+            recovery::remove_swap(path);
+        }
+    }
+
+    /** Inserts pasted text as is (without auto-indent or auto-pairs), as a single edit */
+    fn paste(&mut self, text: &str) {
+        self.checkpoint();
+        self.typing = false;
+        self.desired_col = None;
+        self.delete_selection();
+        let ci = self.cur(self.fix(self.cursor));
+        self.replace(ci..ci, text);
+        self.cursor = self.ipos(ci + text.len());
+        self.saved = false;
+    }
+
+    /** Splits the line at the cursor, carrying its indentation over to the new line */
+    fn newline(&mut self, settings: &EditorSettings) {
+        let cur = self.fix(self.cursor);
+        let ci = self.cur(cur);
+        if !settings.auto_indent {
+            self.replace(ci..ci, "\n");
+            self.cursor = self.ipos(ci+1);
+            return;
+        }
+        let line = self.line(cur.1 as usize);
+        let (before, after) = line.split_at(cur.0 as usize);
+        let indent = before[..leading_whitespaces(before)].to_string();
+        let before = before.trim_end();
+        let opener = before.chars().last().filter(|c| closing_bracket(*c).is_some());
+        let closes = opener.and_then(closing_bracket).is_some_and(|c| after.trim_start().starts_with(c));
+        let triggered = self.extension()
+            .and_then(|ext| settings.indent_triggers.get(&ext))
+            .is_some_and(|triggers| triggers.iter().any(|t| before.ends_with(t.as_str())));
+        let mut text = format!("\n{}", indent);
+        if opener.is_some() || triggered {
+            text += &self.indent_unit();
         }
+        let pos = ci + text.len();
+        if closes {
+            text += "\n";
+            text += &indent;
+        }
+        self.replace(ci..ci, &text);
+        self.cursor = self.ipos(pos);
+    }
 
-        return self.body.len();
+    fn cur(&self, c: (i32, i32)) -> usize {
+        let cy = (c.1.max(0) as usize).min(self.line_count()-1);
+        let cx = (c.0.max(0) as usize).min(self.line(cy).len());
+
+        return self.line_starts()[cy] + cx;
     }
 
     fn fix(&self, c: (i32, i32)) -> (i32, i32) {
-        let lines = self.body.split('\n').collect::<Vec<&str>>();
-        
-        let cy = (c.1.max(0) as usize).min(lines.len()-1) as i32;
-        let cx = (c.0.max(0) as usize).min(lines[cy as usize].len()) as i32;
+        let cy = (c.1.max(0) as usize).min(self.line_count()-1);
+        let cx = (c.0.max(0) as usize).min(self.line(cy).len());
 
-        return (cx, cy);
+        return (cx as i32, cy as i32);
     }
 
     fn ipos(&self, i: usize) -> (i32,i32) {
-        if self.body.len() == 0 {
-            return (0, 0)
-        }
-
-        let lines = self.body.split('\n').collect::<Vec<&str>>();
-
-        let mut j = 0usize;
-
-        for y in 0..lines.len() {
-            let nj = j + lines[y].len() + 1;
-            if i >= j && i < nj {
-                return ((i-j) as i32, y as i32)
-            }
-            j = nj;
-        }
+        let i = i.min(self.body.len());
+        let starts = self.line_starts();
+        let y = starts.partition_point(|s| *s <= i) - 1;
 
-        return (lines.last().unwrap().len() as i32, (lines.len()-1) as i32);
+        return ((i - starts[y]) as i32, y as i32);
     }
 
     pub fn write(&mut self) -> bool {
         if let Some(path) = self.path.clone() {
-            fs::write(path, self.body.clone())
+            fs::write(&path, self.body.clone())
                 .and_then(
-                    |_r| Ok(self.saved=true)
+                    |_r| {
+                        self.modified.clear();
+                        self.edits = 0;
+                        recovery::discard(&path);
+                        recovery::remove_swap(&path);
+                        Ok(self.saved=true)
+                    }
                 )
                 .is_ok()
         } else {
@@ -197,6 +1860,15 @@ impl Buffer {
                 .and_then(
                     |r| {
                         self.body=r;
+                        self.line_starts = OnceCell::new();
+                        self.version += 1;
+                        self.folds.clear();
+                        self.modified.clear();
+                        // The history holds byte ranges of the old body, which mean nothing in the new one
+                        self.undo.clear();
+                        self.redo.clear();
+                        self.new_step = true;
+                        self.typing = false;
                         Ok(self.saved=true)
                     }
                 )
@@ -207,6 +1879,17 @@ impl Buffer {
     }
 }
 
+/** Puts a prompt in the status bar after its icon, only showing its end if it is too long, returns where the cursor goes */
+fn prompt(renderer: &mut Renderer, w: u32, h: u32, icon: char, text: &str) -> Option<(i32, i32)> {
+    renderer.get_mut(0, h-1).c = icon;
+    let room = w.saturating_sub(3) as usize;
+    let skip = text.chars().count().saturating_sub(room);
+    let text = text.chars().skip(skip).collect::<String>();
+    let x = text.chars().count() as i32 + 2;
+    renderer.put_text(2, h-1, text);
+    return Some((x, h as i32 - 1));
+}
+
 fn leading_whitespaces(str: &str) -> usize {
     str
         .chars()
@@ -215,29 +1898,215 @@ fn leading_whitespaces(str: &str) -> usize {
         .sum()
 }
 
+/** The indentation and comment marker (`//`, `#` or `>`) starting a line, along with the whitespace following them */
+fn line_prefix(line: &str) -> &str {
+    let indent = leading_whitespaces(line);
+    let rest = &line[indent..];
+    let marker = if rest.starts_with("//") {
+        rest.len() - rest.trim_start_matches(['/', '!']).len()
+    } else if rest.starts_with('#') {
+        rest.len() - rest.trim_start_matches('#').len()
+    } else if rest.starts_with('>') {
+        rest.len() - rest.trim_start_matches(['>', ' ']).len()
+    } else {
+        return &line[..indent];
+    };
+    let rest = &rest[marker..];
+    let space = rest.len() - rest.trim_start().len();
+    return &line[..indent+marker+space];
+}
+
+/** The bracket closing `c` if it is an opening bracket */
+fn closing_bracket(c: char) -> Option<char> {
+    match c {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        _ => None,
+    }
+}
+
+/** The bracket opened by `c` if it is a closing bracket */
+fn opening_bracket(c: char) -> Option<char> {
+    match c {
+        ')' => Some('('),
+        ']' => Some('['),
+        '}' => Some('{'),
+        _ => None,
+    }
+}
+
+/** The character auto-inserted after `c` */
+fn closing_pair(c: char) -> Option<char> {
+    match c {
+        '"' | '\'' => Some(c),
+        c => closing_bracket(c),
+    }
+}
+
+/** Which kind of word a character belongs to, for word-wise movement */
+fn char_class(c: char) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+/** The number of columns taken by `c` when displayed at column `col` */
+fn char_width(c: char, col: usize, tab_width: usize) -> usize {
+    if c == '\t' {
+        tab_width - col % tab_width
+    } else if c.is_control() {
+        1
+    } else {
+        c.width().unwrap_or(1)
+    }
+}
+
+/**
+ * Splits `line` into rows at most `width` columns wide, breaking after whitespace when possible.
+ * Returns the byte offset and display column at which each row starts.
+ */
+fn wrap_line(line: &str, width: usize, tab_width: usize) -> Vec<(usize, usize)> {
+    let mut rows = vec![(0usize, 0usize)];
+    let mut col = 0usize;
+    let mut brk: Option<(usize, usize)> = None;
+    for (i, c) in line.char_indices() {
+        let cw = char_width(c, col, tab_width);
+        let (start, start_col) = *rows.last().unwrap();
+        if col + cw - start_col > width && i > start {
+            let row = brk.filter(|b| b.0 > start).unwrap_or((i, col));
+            rows.push(row);
+            brk = None;
+        }
+        col += cw;
+        if c.is_whitespace() {
+            brk = Some((i + c.len_utf8(), col));
+        }
+    }
+    return rows;
+}
+
+/** The display column at which the byte `x` of `line` is drawn */
+fn display_col(line: &str, x: usize, tab_width: usize) -> usize {
+    let mut col = 0usize;
+    for (i, c) in line.char_indices() {
+        if i >= x {
+            break;
+        }
+        col += char_width(c, col, tab_width);
+    }
+    return col;
+}
+
+/** The byte offset of the character of `line` drawn at the display column `col` (or the end of the line) */
+fn col_to_byte(line: &str, col: usize, tab_width: usize) -> usize {
+    let mut c_col = 0usize;
+    for (i, c) in line.char_indices() {
+        let w = char_width(c, c_col, tab_width);
+        if c_col + w > col {
+            return i;
+        }
+        c_col += w;
+    }
+    return line.len();
+}
+
+/** The indentation (in columns) up to which indent guides are drawn on each line, blank lines taking the smallest one around them */
+fn indent_guides(lines: &[&str], tab_width: usize) -> Vec<usize> {
+    let mut indents = lines
+        .iter()
+        .map(|l| (!l.trim().is_empty()).then(|| display_col(l, leading_whitespaces(l), tab_width)))
+        .collect::<Vec<Option<usize>>>();
+    let mut next = 0;
+    let mut after = vec![0; lines.len()];
+    for y in (0..lines.len()).rev() {
+        after[y] = next;
+        next = indents[y].unwrap_or(next);
+    }
+    let mut prev = 0;
+    for y in 0..lines.len() {
+        prev = *indents[y].get_or_insert(prev.min(after[y]));
+    }
+    return indents.into_iter().map(|i| i.unwrap()).collect();
+}
+
+/**
+ * Guesses whether `body` is indented with spaces and by how many columns.
+ * Returns `None` if it has no indentation at all,
+ * the width is `None` when the file is indented with tabs.
+ */
+fn detect_indent(body: &str) -> Option<(bool, Option<usize>)> {
+    let mut tabs = 0usize;
+    let mut spaces = 0usize;
+    let mut widths = [0usize; 9];
+    let mut prev = 0usize;
+    for line in body.split('\n') {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with('\t') {
+            tabs += 1;
+            continue;
+        }
+        let indent = line.chars().take_while(|c| *c == ' ').count();
+        if indent > 0 {
+            spaces += 1;
+        }
+        let delta = indent.abs_diff(prev);
+        if (2..=8).contains(&delta) {
+            widths[delta] += 1;
+        }
+        prev = indent;
+    }
+    if tabs == 0 && spaces == 0 {
+        return None;
+    }
+    if tabs > spaces {
+        return Some((false, None));
+    }
+    let width = (2..=8).rev().max_by_key(|w| widths[*w]).filter(|w| widths[*w] > 0);
+    return Some((true, width));
+}
+
 impl Window for Buffer {
-    fn render(&mut self, _env: &mut Env, renderer: &mut Renderer) {
+    fn render(&mut self, env: &mut Env, renderer: &mut Renderer) {
         renderer.clear();
 
         let w = renderer.buffer.width;
         let h = renderer.buffer.height;
-
-        if w < 10 || h < 3 {
-            renderer.fill(0, 0, w, h, renderer::Cell { c: '!', s: Style::default() });
-            renderer.render();
-            renderer.flip();
-            stdout().flush().unwrap();
-            return;
-        }
-
-        let tw = w - 5u32;
-        let th = h - 2u32;
+        let (sw, nw) = self.gutter(&env.settings.editor);
+        let sb = env.settings.editor.scrollbar as u32;
+        let mm = env.settings.editor.minimap_width as u32;
+
+        let (gx, tw, th) = match self.layout(&env.settings.editor, w, h) {
+            Some(layout) => layout,
+            None => {
+                renderer.paint(0, 0, w, h, Style::default().fg(FOREGROUND).bg(BACKGROUND).clone());
+                let message = if w >= 18 { format!("Too small ({}x{})", w, h) } else { "Too small".to_string() };
+                let message = message.chars().take(w as usize).collect::<String>();
+                renderer.put_text((w - message.chars().count() as u32) / 2, h / 2, message);
+                renderer.cursor = None;
+                renderer.render();
+                renderer.flip();
+                stdout().flush().unwrap();
+                return;
+            }
+        };
+        self.size = (tw, th);
+        self.window = (w, h);
 
         let cur = self.fix(self.cursor);
-        let (cx, cy) = (cur.0 - self.scroll.0, cur.1 - self.scroll.1);
+        let col = self.col(cur.1, cur.0) as i32;
+        let view = self.view_lines();
+        let (row, row_col) = self.view_pos(&view, cur);
+        let (cx, cy) = (row_col - self.scroll.0, row - self.scroll.1);
         
         renderer.paint(0, h as u32 -1, w as u32, 1, Style::default().fg(FOREGROUND).bg(HEAD).clone());
-        renderer.paint(5, 1, tw, th, Style::default().bg(BACKGROUND).fg(FOREGROUND).clone());
+        renderer.paint(gx, 1, tw, th, Style::default().bg(BACKGROUND).fg(FOREGROUND).clone());
 
         let cursor: Option<(i32,i32)> =
             if let Some(menu) = &self.menu {
@@ -247,55 +2116,51 @@ impl Window for Buffer {
                     },
                     BufferMenuState::Open(message) => {
                         renderer.get_mut(0, h-1).c = '🍵';
-                        renderer.put_text(w - message.len() as u32 -1, h-1, message.clone());
+                        let message = message.chars().take(w.saturating_sub(3) as usize).collect::<String>();
+                        renderer.put_text(w - message.chars().count() as u32 -1, h-1, message);
                         Some((2, h as i32 - 1))
                     }
                     BufferMenuState::Command(cmd) => {
-                        renderer.get_mut(0, h-1).c = '📜';
-                        renderer.put_text(2, h-1, cmd.clone());
-                        Some(((cmd.len()) as i32+2, h as i32 - 1))
+                        prompt(renderer, w, h, '📜', cmd)
                     }
                     BufferMenuState::SaveFailed => {
-                        let message = "Abandon unsaved? ".to_string(); // TODO: Inline below :p
-                        renderer.get_mut(0, h-1).c = '❗';
-                        renderer.put_text(2, h-1, message.clone());
-                        Some(((message.len()) as i32+2, h as i32 - 1))
+                        prompt(renderer, w, h, '❗', "Abandon unsaved? ")
                     }
                     BufferMenuState::Save(path) => {
-                        renderer.get_mut(0, h-1).c = '💾';
-                        renderer.put_text(2, h-1, path.clone());
-                        Some((path.len() as i32+2, h as i32-1))
+                        prompt(renderer, w, h, '💾', path)
                     }
                     BufferMenuState::Overwrite(_path) => {
-                        let message = "Overwrite? ".to_string();
-                        renderer.get_mut(0, h-1).c = '💾';
-                        renderer.put_text(2, h-1, message.clone());
-                        Some((message.len() as i32+2, h as i32-1))
+                        prompt(renderer, w, h, '💾', "Overwrite? ")
                     }
                     BufferMenuState::Load(path) => {
-                        renderer.get_mut(0, h-1).c = '📂';
-                        renderer.put_text(2, h-1, path.clone());
-                        Some((path.len() as i32+2, h as i32-1))
+                        prompt(renderer, w, h, '📂', path)
                     }
                     BufferMenuState::ForceLoad => {
-                        let message = "Abandon unsaved? ".to_string();
-                        renderer.get_mut(0, h-1).c = '📂';
-                        renderer.put_text(2, h-1, message.clone());
-                        Some((message.len() as i32+2, h as i32-1))
+                        prompt(renderer, w, h, '📂', "Abandon unsaved? ")
+                    }
+                    BufferMenuState::Goto(location) => {
+                        prompt(renderer, w, h, '🧭', location)
+                    }
+                    BufferMenuState::Recover(_) => {
+                        prompt(renderer, w, h, '🩹', "Unsaved changes were recovered: (r)estore, (d)iff, (i)gnore? ")
                     }
                 }
             }
             else {
-                let fmt = format!("{}:{}",cy+1,cx+1);
-                renderer.put_text(w-1-fmt.len() as u32, h-1, fmt);
+                let fmt = format!("{}:{}",cur.1+1,col+1);
+                let fmt = fmt.chars().take(w.saturating_sub(3) as usize).collect::<String>();
+                renderer.put_text(w.saturating_sub(1+fmt.len() as u32), h-1, fmt);
                 renderer.get_mut(0, h-1).c = '🪶';
+                if !env.keymap.pending.is_empty() {
+                    renderer.put_text(3, h-1, env.keymap.pending.iter().map(keymap::event_name).collect::<Vec<String>>().join(" "));
+                }
                 None
             }
         ;
 
         // renderer.paint(0, 1, 4, th, Style::default().bg(if cursor.is_none() {COMMENT} else {HEAD}).clone());
-        renderer.paint(0, 1, 4, th, Style::default().bg(COMMENT).clone());
-        renderer.paint(4, 1, 1, th, Style::default().fg(HEAD).clone());
+        renderer.paint(0, 1, gx-1, th, Style::default().bg(COMMENT).clone());
+        renderer.paint(gx-1, 1, 1, th, Style::default().fg(HEAD).clone());
 
         {
             renderer.paint(0, 0, w as u32, 1, Style::default().fg(FOREGROUND).bg(HEAD).clone());
@@ -310,53 +2175,239 @@ impl Window for Buffer {
                 )
                 .unwrap_or("<new>".to_string())
             ;
-            let off = w/2-(path.len() as u32)/2;
+            let path = path.chars().take(w.saturating_sub(2) as usize).collect::<String>();
+            let off = (w/2).saturating_sub(path.chars().count() as u32/2);
             renderer.put_text(off, 0, path);
             if !self.saved {
                 renderer.put_text(w-1, 0, "M".to_string());
             }
         }
 
-        let body = self.body.clone();
-        let lines = body.split('\n').collect::<Vec<&str>>();
-
-        if cy >= 0 && (cy as u32) <= th {
-            renderer.paint(5, (cy+1) as u32, (w-5) as u32, 1, Style::default().fg(FOREGROUND).bg(CURRENT).clone());
-            renderer.paint(0, (cy+1) as u32, 4, 1, Style::default().fg(COMMENT).bg(FOREGROUND).clone());
-        }
+        let starts = self.line_starts();
 
-        let mut bi = 0;
+        let selection = self.selection_range();
 
         for y in 0 .. th {
-            renderer.put_text(3, y+1, "~┃".to_string());
+            if nw > 0 {
+                renderer.get_mut(gx-2, y+1).c = '~';
+            }
+            renderer.get_mut(gx-1, y+1).c = '┃';
+        }
+
+        let colors = &env.settings.color;
+        let rulers = if self.show.rulers { env.settings.editor.rulers.clone() } else { Vec::new() }
+            .into_iter()
+            .map(|r| r as i32 - 1 - self.scroll.0)
+            .filter(|x| *x >= 0 && (*x as u32) < tw)
+            .map(|x| x as u32 + gx)
+            .collect::<Vec<u32>>();
+        for x in rulers.iter() {
+            renderer.paint(*x, 1, 1, th, Style::default().fg(FOREGROUND).bg(colors.ruler).clone());
         }
+        // Only the lines on screen get indent guides, along with the closest non-blank ones around them which blank lines go by
+        let (mut top, mut bottom) = (
+            view.get(self.scroll.1.max(0) as usize).map(|v| v.y as usize).unwrap_or(0),
+            view.get((self.scroll.1.max(0) as usize + th as usize).min(view.len()) - 1).map(|v| v.y as usize).unwrap_or(0),
+        );
+        let guides = if self.show.indent_guides {
+            while top > 0 && self.line(top).trim().is_empty() {
+                top -= 1;
+            }
+            while bottom+1 < starts.len() && self.line(bottom).trim().is_empty() {
+                bottom += 1;
+            }
+            indent_guides(&(top ..= bottom.max(top)).map(|y| self.line(y)).collect::<Vec<&str>>(), self.tab_width)
+        } else {
+            Vec::new()
+        };
+
+        // Lines left visible by the folds, to count relative line numbers
+        let visible = view.iter().filter(|v| v.start == 0).map(|v| v.y).collect::<Vec<i32>>();
+        let current = visible.partition_point(|y| *y < cur.1) as i32;
 
         for j in 0 .. th {
-            let ii = j as i32 + self.scroll.1;
-            if ii < 0 || ii > (th as i32) {
+            let vi = j as i32 + self.scroll.1;
+            if vi < 0 || vi as usize >= view.len() {
                 continue
             }
-            let i = ii as u32;
-            // if let Some(line) = lines.get((i as i32+self.scroll.) as usize) {
-            if let Some(line) = lines.get(i as usize) {
-                renderer.put_text(0, j+1, {let s = format!("{: >4}",i+1); s[s.len()-4..s.len()].to_string()});
-                for l in 0usize .. line.len() {
-                    let x = l as i32 -self.scroll.0;
-                    if x > 0 {
-                        if x as u32 >= tw { 
-                            break;
+            let v = view[vi as usize];
+            let i = v.y as u32;
+            if (i as usize) < starts.len() {
+                let line = self.line(i as usize);
+                if v.y == cur.1 {
+                    renderer.paint(gx, j+1, tw, 1, Style::default().fg(FOREGROUND).bg(CURRENT).clone());
+                    renderer.paint(0, j+1, gx-1, 1, Style::default().fg(COMMENT).bg(FOREGROUND).clone());
+                    for x in rulers.iter() {
+                        renderer.get_mut(*x, j+1).s.bg(colors.ruler);
+                    }
+                }
+                if v.start == 0 && nw > 0 {
+                    let n = match env.settings.editor.line_numbers {
+                        LineNumbers::Relative => (visible.partition_point(|y| *y < v.y) as i32 - current).abs(),
+                        LineNumbers::Hybrid if v.y != cur.1 => (visible.partition_point(|y| *y < v.y) as i32 - current).abs(),
+                        _ => v.y+1,
+                    };
+                    renderer.put_text(sw, j+1, format!("{: >1$}", n, nw as usize));
+                } else if nw > 0 {
+                    renderer.get_mut(gx-2, j+1).c = '↪';
+                }
+                if let Some(sign) = self.sign(v.y).filter(|_| v.start == 0 && sw > 0) {
+                    let cell = renderer.get_mut(0, j+1);
+                    cell.c = sign.c;
+                    cell.s.fg(sign.color);
+                }
+                if let Some(end) = self.folded(v.y) {
+                    if v.start == 0 {
+                        let marker = renderer.get_mut(gx-1, j+1);
+                        marker.c = '▸';
+                        marker.s.fg(YELLOW);
+                    }
+                    if v.end == line.len() {
+                        let x = (display_col(line, v.end, self.tab_width) - v.col) as i32 + 1 - self.scroll.0;
+                        for (k, c) in format!("⋯ {} line{}", end - v.y, if end - v.y == 1 { "" } else { "s" }).chars().enumerate() {
+                            let x = x + k as i32;
+                            if x >= 0 && (x as u32) < tw {
+                                let cell = renderer.get_mut(x as u32+gx, j+1);
+                                cell.c = c;
+                                cell.s.fg(COMMENT);
+                            }
+                        }
+                    }
+                }
+                let text_end = line.trim_end().len();
+                let mut col = v.col;
+                for (l, c) in line[v.start..v.end].char_indices() {
+                    let l = l + v.start;
+                    let cw = char_width(c, col, self.tab_width);
+                    let selected = selection.is_some_and(|(start, end)| (start..end).contains(&(starts[i as usize]+l)));
+                    let trailing = self.show.trailing && l >= text_end && v.y != cur.1;
+                    let vcol = (col - v.col) as i32;
+                    let whole = vcol >= self.scroll.0 && (vcol + cw as i32 - self.scroll.0) as u32 <= tw;
+                    for k in 0 .. cw {
+                        let x = vcol + k as i32 - self.scroll.0;
+                        if x >= 0 && (x as u32) < tw {
+                            let cell = renderer.get_mut(x as u32+gx, j+1);
+                            cell.c = if !whole || k > 0 && c == '\t' {
+                                ' '
+                            } else if c == '\t' {
+                                if self.show.tabs || trailing { '→' } else { ' ' }
+                            } else if c == ' ' {
+                                if self.show.spaces || trailing { '·' } else { ' ' }
+                            } else if k == 0 {
+                                c
+                            } else {
+                                renderer::CONTINUATION
+                            };
+                            if (c == ' ' || c == '\t') && cell.c != ' ' {
+                                cell.s.fg(colors.whitespace);
+                            }
+                            if trailing {
+                                cell.s.bg(colors.trailing);
+                            }
+                            if selected {
+                                cell.s.bg(SELECTION);
+                            }
+                        }
+                    }
+                    col += cw;
+                    if (col - v.col) as i32 - self.scroll.0 >= tw as i32 {
+                        break;
+                    }
+                }
+                if v.col == 0 {
+                    for g in (0..guides.get((i as usize).wrapping_sub(top)).copied().unwrap_or(0)).step_by(self.tab_width) {
+                        let x = g as i32 - self.scroll.0;
+                        if x >= 0 && (x as u32) < tw {
+                            let cell = renderer.get_mut(x as u32+gx, j+1);
+                            cell.c = '│';
+                            cell.s.fg(colors.indent_guide);
                         }
-                        renderer.get_mut(x as u32+5, j+1).c = line.as_bytes()[l] as char;
                     }
                 }
-                renderer.put_text(5, j+1, line[(self.scroll.0 as usize).min(line.len())..line.len().min(tw as usize)].to_string());
-                let nbi = bi + line.len() + 1;
-                bi = nbi;
+                if self.show.eol && v.end == line.len() {
+                    let x = (display_col(line, v.end, self.tab_width) - v.col) as i32 - self.scroll.0;
+                    if x >= 0 && (x as u32) < tw {
+                        let cell = renderer.get_mut(x as u32+gx, j+1);
+                        cell.c = '¬';
+                        cell.s.fg(colors.eol);
+                    }
+                }
+            }
+        }
+
+        let rows = view.len() as i32;
+
+        if mm > 0 {
+            let mx = gx + tw;
+            // Each cell of the minimap holds 4 rows of the view, which scrolls along with the view when they don't all fit
+            let shown = th as i32 * 4;
+            let first = if rows <= shown { 0 } else { (self.scroll.1 * (rows - shown) / (rows - th as i32)).clamp(0, rows - shown) };
+            renderer.paint(mx, 1, mm, th, Style::default().fg(colors.minimap).bg(BACKGROUND).clone());
+            for k in 0 .. th as i32 {
+                let (a, b) = (first + k*4, first + k*4 + 4);
+                if a < self.scroll.1 + th as i32 && b > self.scroll.1 && a < rows {
+                    renderer.paint(mx, k as u32+1, mm, 1, Style::default().fg(colors.minimap).bg(colors.minimap_view).clone());
+                }
+            }
+            for r in first .. (first + shown).min(rows) {
+                let v = view[r as usize];
+                let line = self.line(v.y as usize);
+                let dy = (r - first) as usize;
+                let mut col = v.col;
+                for c in line[v.start..v.end].chars() {
+                    let dx = (col - v.col) / MINIMAP_SCALE;
+                    col += char_width(c, col, self.tab_width);
+                    if dx >= mm as usize * 2 {
+                        break;
+                    }
+                    if c.is_whitespace() {
+                        continue;
+                    }
+                    let dot = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]][dy % 4][dx % 2];
+                    let cell = renderer.get_mut(mx + (dx / 2) as u32, (dy / 4) as u32 + 1);
+                    let dots = if ('\u{2800}'..='\u{28ff}').contains(&cell.c) { cell.c as u32 - 0x2800 } else { 0 };
+                    cell.c = char::from_u32(0x2800 + (dots | dot)).unwrap();
+                }
+            }
+        }
+
+        if sb > 0 {
+            let x = w - 1;
+            let total = rows.max(1) as u32;
+            let (start, size) = if total <= th {
+                (0, th)
+            } else {
+                let size = (th * th / total).max(1);
+                ((self.scroll.1.max(0) as u32 * th / total).min(th - size), size)
+            };
+            renderer.paint(x, 1, 1, th, Style::default().bg(colors.scrollbar).clone());
+            renderer.paint(x, start+1, 1, size, Style::default().bg(colors.scrollbar_thumb).clone());
+            let total = total.max(th);
+            let marks = self.modified
+                .iter()
+                .map(|y| (*y, colors.modified))
+                .chain(self.signs.iter().map(|(y, s)| (*y, s.color)));
+            for (y, color) in marks {
+                let (row, _) = self.view_pos(&view, (0, y));
+                let cell = renderer.get_mut(x, row as u32 * th / total + 1);
+                cell.c = '━';
+                cell.s.fg(color);
+            }
+        }
+
+        if let Some((a, b)) = self.bracket_pair() {
+            for i in [a, b] {
+                let (row, row_col) = self.view_pos(&view, self.ipos(i));
+                let (bx, by) = (row_col - self.scroll.0, row - self.scroll.1);
+                if bx >= 0 && (bx as u32) < tw && by >= 0 && (by as u32) < th {
+                    renderer.get_mut(bx as u32+gx, by as u32+1).s.fg(YELLOW).bold(true).underline(true);
+                }
             }
         }
 
-        if self.menu.is_none() && cx >= 0 && (cx as u32) < tw && cy >= 0 && (cy as u32) <= th {
-            renderer.get_mut((cx+5) as u32, (cy+1) as u32).s.reverse(Instant::now()<self.hold_blink||Instant::now().duration_since(self.hold_blink).as_millis()%1000 < 500);
+        self.blink = self.cursor_on();
+        if self.menu.is_none() && cx >= 0 && (cx as u32) < tw && cy >= 0 && (cy as u32) < th {
+            renderer.get_mut(cx as u32+gx, (cy+1) as u32).s.reverse(self.blink);
         }
 
         /*renderer.put(&TextOptions{
@@ -369,27 +2420,28 @@ impl Window for Buffer {
             style: None
         });*/
 
-        renderer.apply(5, 1, tw, th, &|cell, _x, _y| {
+        renderer.apply(gx, 1, tw, th, &|cell, _x, _y| {
             if cell.c < '\x20' {
                 cell.c = char::from_u32((cell.c as u32) + 0x2400u32).unwrap();
                 cell.s = cell.s.clone().bg(RED).clone();
             }
         });
 
-        print!("\x1b[{};1H",h);
-        if let Some((x,y)) = cursor {
-            print!("\x1b[?25h\x1b[{};{}H",y+1,x+1);
-        } else {
-            print!("\x1b[?25l");
-        }
-
+        renderer.cursor = cursor.map(|(x, y)| (x as u32, y as u32));
         renderer.render();
         renderer.flip();
         stdout().flush().unwrap();
+        self.redraw = false;
     }
 
     fn key_pressed(&mut self, env: &mut Env, ev: Event) {
-        if let Some(menu) = &mut self.menu {
+        self.redraw = true;
+        let cursor = self.cursor;
+        let version = self.version;
+        if let Event::Mouse(mouse) = ev {
+            self.mouse(env, mouse);
+            self.hold_blink = Instant::now() + BLINK_HOLD;
+        } else if let Some(menu) = &mut self.menu {
             let mut new_menu = menu.clone();
             match ev {
                 Event::Key(key) => {
@@ -524,17 +2576,49 @@ impl Window for Buffer {
                                         path.push(c);
                                         new_menu = menu.clone();
                                     }
-                                    else if c == '\n' {
+                                    else if c == '\n' || c == '\r' {
                                         let p = self.path.clone();
                                         self.path = Some(path.clone());
                                         if self.read() {
-                                            new_menu = BufferMenuState::Open(format!("Read {} bytes",self.body.len()));
+                                            if let Some(p) = p.filter(|p| Some(p) != self.path.as_ref()) {
+                                                recovery::remove_swap(&p);
+                                            }
+                                            new_menu = self.recovery_menu()
+                                                .unwrap_or(BufferMenuState::Open(format!("Read {} bytes",self.body.len())));
                                         } else {
                                             new_menu = BufferMenuState::Open("Could not read".to_string());
                                             self.path = p;
                                         }
                                     }
                                 }
+                                BufferMenuState::Goto(location) => {
+                                    if c.is_ascii_digit() || c == ':' {
+                                        location.push(c);
+                                        new_menu = menu.clone();
+                                    }
+                                    else if c == '\n' || c == '\r' {
+                                        let location = location.clone();
+                                        new_menu = if self.goto(&location) {
+                                            BufferMenuState::None
+                                        } else {
+                                            BufferMenuState::Open(format!("Invalid location '{}'",location))
+                                        };
+                                    }
+                                }
+                                BufferMenuState::Recover(text) => {
+                                    if c == 'r' || c == 'd' {
+                                        let text = text.clone();
+                                        self.recover(text, c == 'd');
+                                        new_menu = BufferMenuState::None;
+                                    }
+                                    else if c == 'i' {
+                                        if let Some(path) = &self.path {
+                                            recovery::discard(path);
+                                            recovery::remove_swap(path);
+                                        }
+                                        new_menu = BufferMenuState::None;
+                                    }
+                                }
                                 BufferMenuState::SaveFailed => {
                                     if c == 'y' || c == 'Y' {
                                         env.running = false;
@@ -565,12 +2649,27 @@ impl Window for Buffer {
                                     }
                                     new_menu = menu.clone();
                                 }
+                                BufferMenuState::Goto(location) => {
+                                    location.pop();
+                                    new_menu = menu.clone();
+                                }
                                 _ => {} 
                             }
                         }
                         _ => {}
                     }
                 }
+                Event::Unsupported(ref seq) if input::is_paste(seq) => {
+                    match menu {
+                        BufferMenuState::Command(text) | BufferMenuState::Save(text) | BufferMenuState::Load(text) | BufferMenuState::Goto(text) => {
+                            if let Some(pasted) = input::pasted(seq) {
+                                text.extend(pasted.chars().filter(|c| !c.is_control()));
+                            }
+                            new_menu = menu.clone();
+                        }
+                        _ => {}
+                    }
+                }
                 _ => {}
             }
             self.menu = match new_menu {
@@ -579,113 +2678,105 @@ impl Window for Buffer {
             };
         } else {
             match ev {
-                Event::Key(key) => {
-                    // println!("{:?}",key);
-                    let mut blink = false;
-                    match key {
-                        Key::Esc => {
-                            self.menu = Some(BufferMenuState::Open(String::new()));
-                        }
-                        Key::Char(c) => {
-                            let ci = self.cur(self.fix(self.cursor));
-                            if c == '\x09' {
-                                self.body.insert_str(ci, "    ");
-                                self.cursor = self.ipos(ci+4);
-                            } else if c == '\r' {
-                                self.body.insert(ci, '\n');
-                                self.cursor = self.ipos(ci+1);
-                            } else {
-                                self.body.insert(ci, c);
-                                self.cursor = self.ipos(ci+1);
-                            }
-                            self.saved = false;
-                        }
-                        Key::Backspace => {
-                            let ci = self.cur(self.fix(self.cursor));
-                            if ci > 0 {
-                                self.body.remove(ci-1);
-                                self.cursor = self.ipos(ci-1);
-                                self.saved = false;
-                            }
-                        }
-                        /*Key::Ctrl(_c) => {
-                            
-                        }*/
-                        Key::Alt(_c) => {
-                            // ?
-                        }
-                        Key::Up => {
-                            self.cursor.1 -= 1;
-                            if self.cursor.1 < 0 {
-                                self.cursor = (0,0);
-                            }
-                        }
-                        Key::Down => {
-                            let lines = self.body.split('\n').collect::<Vec<&str>>();
-                            self.cursor.1 += 1;
-                            if self.cursor.1 as usize >= lines.len() {
-                                self.cursor = ((lines.len()-1) as i32,lines.last().unwrap().len() as i32);
-                            }
-                        }
-                        Key::Left => {
-                            let ci = self.cur(self.fix(self.cursor));
-                            if ci != 0 {
-                                self.cursor = self.ipos(ci-1);
-                            }
-                        }
-                        Key::Right => {
-                            self.cursor = self.ipos(self.cur(self.fix(self.cursor))+1)
-                        }
-                        Key::CtrlUp => {
-                            if self.scroll.1 > 0 {
-                                self.scroll.1 -= 1;
-                            }
-                        }
-                        Key::CtrlDown => {
-                            let lines = self.body.split('\n').collect::<Vec<&str>>();
-                            if (self.scroll.1 as usize) +1 < lines.len() {
-                                self.scroll.1 += 1;
-                            }
-                        }
-                        Key::CtrlLeft => {
-                            if self.scroll.0 > 0 {
-                                self.scroll.0 -= 1;
-                            }
-                        }
-                        Key::CtrlRight => {
-                            let maxlen = self.body.split('\n').map(|l|l.len()).max().unwrap();
-                            if (self.scroll.0 as usize) +1 < maxlen {
-                                self.scroll.0 += 1;
-                            }
-                        }
-                        Key::End => {
-                            self.cursor.0 = self.body.split('\n').nth(self.cursor.1 as usize).unwrap().len() as i32;
-                        }
-                        Key::Home => {
-                            if env.settings.controls.smart_home {
-                                let line = self.body.split('\n').nth(self.cursor.1 as usize).unwrap();
-                                let i = leading_whitespaces(line) as i32;
-
-                                self.cursor.0 = if self.cursor.0 == i { 0 } else { i };
-                            } else {
-                                self.cursor.0 = 0;
+                Event::Unsupported(ref seq) if seq == input::FOCUS_OUT => {
+                    if env.settings.autosave.write_on_focus_lost && !self.saved && self.path.is_some() {
+                        self.write();
+                    }
+                }
+                Event::Unsupported(ref seq) if input::is_paste(seq) => {
+                    if let Some(text) = input::pasted(seq) {
+                        self.paste(&text);
+                    }
+                    self.hold_blink = Instant::now() + BLINK_HOLD;
+                }
+                Event::Key(_) | Event::Unsupported(_) => {
+                    match env.keymap.feed(ev) {
+                        Binding::Action(action) => self.run(env, action),
+                        Binding::Pending => {}
+                        Binding::Unbound(keys) => {
+                            if let [Event::Key(Key::Char(c))] = keys[..] {
+                                if !c.is_control() {
+                                    self.desired_col = None;
+                                    if !self.typing {
+                                        self.checkpoint();
+                                        self.typing = true;
+                                    }
+                                    self.delete_selection();
+                                    self.type_char(c, &env.settings.editor);
+                                    if self.auto_wrap && !c.is_whitespace() {
+                                        self.break_line(env.settings.editor.text_width);
+                                    }
+                                    self.saved = false;
+                                }
                             }
                         }
-                        _ => {
-                            let ci = self.cur(self.fix(self.cursor));
-                            let s = format!("{:?}",key);
-                            self.body.insert_str(ci, s.as_str());
-                            self.cursor = self.ipos(ci+s.len());
-                            blink = true;
-                        }
-                    }
-                    if !blink {
-                        self.hold_blink = Instant::now() + BLINK_HOLD;
                     }
+                    self.hold_blink = Instant::now() + BLINK_HOLD;
                 }
                 _ => {}
             }
         }
+        if self.version != version {
+            self.edits += 1;
+            self.last_edit = Instant::now();
+            if env.settings.autosave.swap && self.edits >= env.settings.autosave.swap_edits {
+                self.write_swap();
+            }
+        }
+        self.reveal(self.fix(self.cursor).1);
+        if self.cursor != cursor {
+            self.scroll_to_cursor(&env.settings.editor);
+        }
+    }
+
+    fn unsaved(&self) -> Option<(Option<&str>, &str)> {
+        return (!self.saved).then_some((self.path.as_deref(), &self.body));
+    }
+
+    fn tick(&mut self, env: &mut Env) {
+        let settings = &env.settings.autosave;
+        if self.saved {
+            return;
+        }
+        if settings.write_when_idle && self.path.is_some() && self.last_edit.elapsed() >= Duration::from_secs(settings.idle_delay) {
+            self.write();
+            self.redraw = true;
+        } else if settings.swap && self.edits > 0 {
+            self.write_swap();
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(path) = &self.path {
+            recovery::remove_swap(path);
+        }
+    }
+
+    fn needs_redraw(&self) -> bool {
+        return self.redraw || self.menu.is_none() && self.blink != self.cursor_on();
+    }
+
+    fn next_frame(&self) -> Option<Instant> {
+        if self.menu.is_some() {
+            return None;
+        }
+        let blinks = Instant::now().saturating_duration_since(self.hold_blink).as_millis() / BLINK_RATE.as_millis();
+        return Some(self.hold_blink + BLINK_RATE * (blinks as u32 + 1));
+    }
+
+    fn resized(&mut self, env: &mut Env, width: u32, height: u32) {
+        self.redraw = true;
+        self.window = (width, height);
+        if let Some((_, tw, th)) = self.layout(&env.settings.editor, width, height) {
+            self.size = (tw, th);
+        }
+        self.cursor = self.fix(self.cursor);
+        let last = self.view_lines().len() as i32 - 1;
+        self.scroll.1 = self.scroll.1.clamp(0, last.max(0));
+        if self.wrap {
+            self.scroll.0 = 0;
+        }
+        self.scroll_to_cursor(&env.settings.editor);
     }
 }
 
@@ -718,32 +2809,45 @@ struct Env {
     windows: Windows,
     running: bool,
     settings: Settings,
+    keymap: KeyMap,
 }
 
-fn raw_stdin() -> termios {
-    let mut termios = core::mem::MaybeUninit::uninit();
-    unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()); }
-    let mut termios = unsafe { termios.assume_init() };
-    let v =  termios.clone();
-    termios.c_lflag &= !(libc::IGNBRK | libc::ICANON | libc::ECHO);
-    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios); }
-    return v;
-}
+fn main() {
+    let settings = match Settings::load(SETTINGS_PATH) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{}",err);
+            panic!();
+        }
+    };
 
-fn unraw_stdin(original: termios) {
-    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original); }
-}
+    let keymap = match KeyMap::new(&settings.keys) {
+        Ok(keymap) => keymap,
+        Err(err) => {
+            eprintln!("{}",err);
+            panic!();
+        }
+    };
 
-fn main() {
-    print!("\x1b[?1049h");
-    let original_termios = raw_stdin();
+    let terminal = match Terminal::enter() {
+        Ok(terminal) => terminal,
+        Err(err) => {
+            eprintln!("Could not set up the terminal: {}",err);
+            std::process::exit(1);
+        }
+    };
 
     let mut renderer = Renderer::new();
 
-    let mut render_clk = Clock::new(Duration::from_millis(16));
+    // Input that was read but not handled yet, starting with what gets typed while the terminal is being queried
+    let mut parser = input::Parser::new();
+    renderer.synchronized = match settings.terminal.synchronized_output {
+        SynchronizedOutput::Auto => terminal.supports(2026, &mut parser.bytes),
+        SynchronizedOutput::Always => true,
+        SynchronizedOutput::Never => false,
+    };
 
-    let mut events = Arc::new(Mutex::new(VecDeque::<(Event,Vec<u8>)>::new()));
-    let mut tevents = events.clone();
+    let mut autosave_clk = Clock::new(Duration::from_secs(settings.autosave.interval.max(1)));
 
     let mut args = args();
 
@@ -769,31 +2873,11 @@ fn main() {
         body.push(c);
     }*/
 
-    // Kind of annoying, but stdin reads are blocking,
-    // so they need to be done in a dedicated thread
-    spawn(move||{
-        for e in stdin().events_and_raw() {
-            tevents.lock().unwrap().push_front(e.unwrap());
-        }
-    });
-
     let mut env = Env{
         windows: Windows::new(),
         running: true,
-        settings: Settings{
-            color: ColorSettings{
-                background: Color::RGB(40, 42, 54),
-                head: Color::RGB(68, 71, 90),
-                current: Color::RGB(50, 52, 64),
-                foreground: Color::RGB(248, 248, 242),
-                comment: Color::RGB(98, 114, 164),
-                red: Color::RGB(255, 85, 85),
-                yellow: Color::RGB(241, 250, 140),
-            },
-            controls: ControlSettings{
-                smart_home: true,
-            },
-        }
+        settings,
+        keymap,
     };
 
     let mut dst = "".to_string();
@@ -802,7 +2886,7 @@ fn main() {
         eprintln!("{}",err);
         panic!();
     }
-    if let Err(err) = fs::write("./settings.toml".to_string(), dst) {
+    if let Err(err) = fs::write(SETTINGS_PATH, dst) {
         eprintln!("{}",err);
         panic!();
     }
@@ -810,33 +2894,222 @@ fn main() {
     env.windows.push(
         Box::new(
             if let Some(filename) = filename {
-                Buffer::from_file(&filename)
+                Buffer::from_file(&filename, &env.settings.editor)
             }
             else {
-                Buffer::new()
+                Buffer::new(&env.settings.editor)
             }
         ), 
         true
     );
 
-    while env.running {
-        sleep(Duration::from_millis(1));
+    // Panics are caught to get a chance at saving the buffers before going down
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut buf = [0u8; 4096];
+        // Whether stdin is still open
+        let mut input = true;
+        // Whether the whole screen needs to be rendered again, regardless of the windows
+        let mut dirty = true;
+        let mut next_frame = None;
+        while env.running {
+            if let Some(signal) = terminal::terminated() {
+                return Some(signal);
+            }
+
+            if terminal::continued() {
+                if let Err(err) = terminal.resume() {
+                    panic!("Could not set the terminal back up: {}", err);
+                }
+                renderer.void();
+                dirty = true;
+            }
+
+            if terminal::resized() {
+                renderer.resize(renderer::screen_size());
+                let (w, h) = renderer.size;
+                let e = (&mut env) as *mut Env;
+                for window in env.windows.windows.iter_mut() {
+                    unsafe { window.resized(&mut *e, w, h); }
+                }
+                dirty = true;
+            }
+
+            for (ev, _keys) in parser.parse() {
+                let e = (&mut env) as *mut Env;
+                unsafe { env.windows.focused().key_pressed(&mut *e, ev); }
+            }
+
+            if autosave_clk.tick() {
+                let e = (&mut env) as *mut Env;
+                for window in env.windows.windows.iter_mut() {
+                    unsafe { window.tick(&mut *e); }
+                }
+            }
+
+            if dirty || env.windows.focused().needs_redraw() {
+                let e = (&mut env) as *mut Env;
+                unsafe { env.windows.focused().render(&mut *e, &mut renderer); }
+                next_frame = env.windows.focused().next_frame();
+                dirty = false;
+            }
+
+            // Sleeps until there is input, a signal, or something scheduled
+            let deadline = next_frame.into_iter().chain([autosave_clk.next]).min();
+            let ready = match terminal.wait(input, deadline) {
+                Ok(ready) => ready,
+                Err(err) => panic!("Could not wait for input: {}", err),
+            };
+            if ready {
+                match terminal.read(&mut buf) {
+                    Ok(0) => input = false,
+                    Ok(n) => parser.bytes.extend_from_slice(&buf[..n]),
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(err) => panic!("Could not read the input: {}", err),
+                }
+            }
+        }
+        return None;
+    }));
 
-        // Process Events
-        while let Some(event) = events.lock().unwrap().pop_back() {
-            let (ev, _keys) = event;
-            let e = (&mut env) as *mut Env;
-            unsafe { env.windows.focused().key_pressed(&mut *e, ev); }
+    if let Ok(None) = result {
+        for window in env.windows.windows.iter_mut() {
+            window.close();
         }
+        drop(terminal);
+        return;
+    }
 
-        if render_clk.tick() {
-            let e = (&mut env) as *mut Env;
-            unsafe { env.windows.focused().render(&mut *e, &mut renderer); }
+    let mut recovered = Vec::new();
+    for (i, window) in env.windows.windows.iter().enumerate() {
+        if let Some((path, body)) = window.unsaved() {
+            recovered.push(recovery::save(path, body, i));
         }
+    }
+    drop(terminal);
+    for file in recovered {
+        match file {
+            Ok(file) => eprintln!("Unsaved changes written to {}", file.display()),
+            Err(err) => eprintln!("Could not save unsaved changes: {}", err),
+        }
+    }
+    match result {
+        Ok(Some(signal)) => terminal::reraise(signal),
+        Err(panic) => panic::resume_unwind(panic),
+        Ok(None) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** A buffer holding `body`, with an empty undo history */
+    fn buffer(body: &str) -> Buffer {
+        let mut buffer = Buffer::new(&EditorSettings::default());
+        buffer.replace(0..0, body);
+        buffer.undo.clear();
+        buffer.checkpoint();
+        return buffer;
+    }
+
+    /** The lines of the buffer as `line` gives them */
+    fn lines(buffer: &Buffer) -> Vec<&str> {
+        return (0..buffer.line_count()).map(|y| buffer.line(y)).collect();
+    }
+
+    #[test]
+    fn wraps_lines() {
+        assert_eq!(wrap_line("hello world foo", 8, 4), vec![(0, 0), (6, 6), (12, 12)]);
+        assert_eq!(wrap_line("abcdefghij", 4, 4), vec![(0, 0), (4, 4), (8, 8)]);
+        assert_eq!(wrap_line("short", 8, 4), vec![(0, 0)]);
+        assert_eq!(wrap_line("", 8, 4), vec![(0, 0)]);
+        assert_eq!(wrap_line("\tab", 4, 4), vec![(0, 0), (1, 4)]);
+        assert_eq!(wrap_line("ééééé", 2, 4), vec![(0, 0), (4, 2), (8, 4)]);
+    }
+
+    #[test]
+    fn detects_indentation() {
+        assert_eq!(detect_indent("fn a() {\n    b();\n    if c {\n        d();\n    }\n}\n"), Some((true, Some(4))));
+        assert_eq!(detect_indent("a:\n  b:\n    c\n  d\n"), Some((true, Some(2))));
+        assert_eq!(detect_indent("fn a() {\n\tb();\n\tc();\n}\n"), Some((false, None)));
+        assert_eq!(detect_indent("a\nb\n\nc\n"), None);
+    }
+
+    #[test]
+    fn groups_edits_into_steps() {
+        let mut buffer = buffer("ab");
+        buffer.replace(2..2, "c");
+        buffer.replace(3..3, "d");
+        buffer.checkpoint();
+        buffer.replace(0..1, "é");
+        assert_eq!(buffer.body, "ébcd");
+        assert_eq!(buffer.undo.len(), 2);
+        buffer.undo();
+        assert_eq!(buffer.body, "abcd");
+        buffer.undo();
+        assert_eq!(buffer.body, "ab");
+        buffer.undo();
+        assert_eq!(buffer.body, "ab");
+        buffer.redo();
+        assert_eq!(buffer.body, "abcd");
+        buffer.redo();
+        assert_eq!(buffer.body, "ébcd");
+        buffer.undo();
+        buffer.replace(0..0, "x");
+        assert!(buffer.redo.is_empty());
+        assert_eq!(buffer.body, "xabcd");
+    }
+
+    #[test]
+    fn undoes_steps_of_several_changes() {
+        let mut buffer = buffer("one\ntwo\nthree");
+        buffer.replace(8..13, "3");
+        buffer.replace(0..3, "1");
+        assert_eq!(buffer.body, "1\ntwo\n3");
+        assert_eq!(buffer.undo.len(), 1);
+        buffer.undo();
+        assert_eq!(buffer.body, "one\ntwo\nthree");
+        buffer.redo();
+        assert_eq!(buffer.body, "1\ntwo\n3");
+    }
+
+    #[test]
+    fn keeps_line_starts_in_sync() {
+        let mut buffer = buffer("a\nbb\nccc");
+        let _ = buffer.line_starts();
+        buffer.replace(1..5, "x\ny\nz\n");
+        assert_eq!(lines(&buffer), vec!["ax", "y", "z", "ccc"]);
+        buffer.checkpoint();
+        buffer.replace(0..buffer.body.len(), "");
+        assert_eq!(lines(&buffer), vec![""]);
+        buffer.undo();
+        assert_eq!(lines(&buffer), vec!["ax", "y", "z", "ccc"]);
+        let fresh = std::iter::once(0).chain(buffer.body.match_indices('\n').map(|(i, _)| i + 1)).collect::<Vec<usize>>();
+        assert_eq!(buffer.line_starts(), fresh.as_slice());
+    }
 
+    #[test]
+    fn moves_folds_with_their_lines() {
+        let mut buffer = buffer("a\nb\nc\nd");
+        buffer.folds = vec![(2, 3)];
+        buffer.replace(0..0, "x\n");
+        assert_eq!(buffer.folds, vec![(3, 4)]);
+        buffer.replace(0..2, "");
+        assert_eq!(buffer.folds, vec![(2, 3)]);
+        buffer.replace(4..7, "");
+        assert!(buffer.folds.is_empty());
     }
 
-    print!("\x1b[?1049l\x1b[?25h");
-    stdout().flush().unwrap();
-    unraw_stdin(original_termios);
+    #[test]
+    fn reflows_paragraphs() {
+        let mut plain = buffer("one two three four five");
+        plain.reflow(0, 0, 10);
+        assert_eq!(plain.body, "one two\nthree four\nfive");
+        assert_eq!(plain.cursor, (4, 2));
+        let mut comment = buffer("// one two\n// three four five");
+        comment.reflow(0, 1, 14);
+        assert_eq!(comment.body, "// one two\n// three four\n// five");
+        comment.undo();
+        assert_eq!(comment.body, "// one two\n// three four five");
+    }
 }
@@ -0,0 +1,181 @@
+use std::{env, fs, io::{self, BufRead, Read, Write}, os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt}, path::{Path, PathBuf}, process};
+
+/// Largest number of line pairs compared by `changed_lines` before it settles for a rougher answer
+const DIFF_LIMIT: usize = 4_000_000;
+
+/**
+ * The directory the unsaved buffers get written to when the editor goes down:
+ * `$XDG_STATE_HOME/feathrs/recovery`, `~/.local/state/feathrs/recovery` when it isn't set.
+ * There is none without a home, rather than one shared with other users.
+ */
+fn recovery_dir() -> io::Result<PathBuf> {
+    let state = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").filter(|dir| !dir.is_empty()).map(|home| PathBuf::from(home).join(".local/state")))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "neither XDG_STATE_HOME nor HOME is set"))?;
+    return Ok(state.join("feathrs").join("recovery"));
+}
+
+/** The recovery copy of the file at `path`, named after its path with `%` and `/` escaped so that different paths never share one */
+fn file_for(path: &str) -> io::Result<PathBuf> {
+    return Ok(recovery_dir()?.join(path.replace('%', "%25").replace('/', "%2F")));
+}
+
+/**
+ * Writes a recovery copy of a buffer, returns where it went.
+ * Buffers without a file are told apart by `n`.
+ */
+pub fn save(path: Option<&str>, body: &str, n: usize) -> io::Result<PathBuf> {
+    let dir = recovery_dir()?;
+    // Only the user gets to see the copies
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    let file = match path {
+        Some(path) => file_for(path)?,
+        None => dir.join(format!("untitled-{}-{}", process::id(), n)),
+    };
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&file)?.write_all(body.as_bytes())?;
+    return Ok(file);
+}
+
+/** The recovery copy left for the file at `path`, if any */
+pub fn load(path: &str) -> Option<String> {
+    return fs::read_to_string(file_for(path).ok()?).ok();
+}
+
+/** Removes the recovery copy of the file at `path` */
+pub fn discard(path: &str) {
+    if let Ok(file) = file_for(path) {
+        let _ = fs::remove_file(file);
+    }
+}
+
+/** The lines of `new` that are not in `old`, going by the longest common subsequence of their lines */
+pub fn changed_lines(old: &str, new: &str) -> Vec<i32> {
+    let old = old.split('\n').collect::<Vec<&str>>();
+    let new = new.split('\n').collect::<Vec<&str>>();
+    let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+    let limit = old.len().min(new.len()) - prefix;
+    let suffix = old.iter().rev().zip(new.iter().rev()).take(limit).take_while(|(a, b)| a == b).count();
+    let (a, b) = (&old[prefix..old.len()-suffix], &new[prefix..new.len()-suffix]);
+    if a.len() * b.len() > DIFF_LIMIT {
+        return (prefix..new.len()-suffix).map(|y| y as i32).collect();
+    }
+    // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0u32; b.len()+1]; a.len()+1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i+1][j+1] + 1 } else { lcs[i+1][j].max(lcs[i][j+1]) };
+        }
+    }
+    let mut changed = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while j < b.len() {
+        if i < a.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if i < a.len() && lcs[i+1][j] >= lcs[i][j+1] {
+            i += 1;
+        } else {
+            changed.push((prefix + j) as i32);
+            j += 1;
+        }
+    }
+    return changed;
+}
+
+/// First line of swap files, followed by the id of the process writing it
+const SWAP_HEADER: &str = "feathrs swap ";
+
+/** The swap file of the file at `path`, a hidden file next to it (named apart from the `.swp` files of Vim) */
+fn swap_file(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    return path.with_file_name(format!(".{}.feathrs.swp", name));
+}
+
+/**
+ * Whether the file at `swap` is free for this process to write or remove: it is missing,
+ * or it is a swap file of the editor (its first line is `SWAP_HEADER` and a pid) written by this process or by one that is gone
+ */
+fn ours(swap: &Path) -> bool {
+    let file = match fs::File::open(swap) {
+        Ok(file) => file,
+        Err(err) => return err.kind() == io::ErrorKind::NotFound,
+    };
+    let mut header = String::new();
+    if io::BufReader::new(file.take(64)).read_line(&mut header).is_err() {
+        return false;
+    }
+    return match header.trim_end_matches('\n').strip_prefix(SWAP_HEADER).and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) => pid == process::id() || !alive(pid),
+        None => false,
+    };
+}
+
+/**
+ * Writes the swap file of the file at `path` with the current contents of its buffer (readable by the same people as the file),
+ * unless something else already goes by its name or another editor is using it
+ */
+pub fn write_swap(path: &str, body: &str) -> io::Result<()> {
+    let swap = swap_file(path);
+    if !ours(&swap) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} belongs to something else", swap.display())));
+    }
+    let mode = fs::metadata(path).map(|m| m.permissions().mode() & 0o777).unwrap_or(0o600);
+    let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(swap)?;
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    writeln!(file, "{}{}", SWAP_HEADER, process::id())?;
+    return file.write_all(body.as_bytes());
+}
+
+/** Removes the swap file of the file at `path`, unless it isn't ours to remove */
+pub fn remove_swap(path: &str) {
+    let swap = swap_file(path);
+    if ours(&swap) {
+        let _ = fs::remove_file(swap);
+    }
+}
+
+/** The id of the process that wrote the swap file of the file at `path`, and the contents it holds */
+pub fn read_swap(path: &str) -> Option<(u32, String)> {
+    let swap = fs::read_to_string(swap_file(path)).ok()?;
+    let (header, body) = swap.split_once('\n')?;
+    let pid = header.strip_prefix(SWAP_HEADER)?.parse::<u32>().ok()?;
+    return Some((pid, body.to_string()));
+}
+
+/** Whether the process `pid` is still running */
+pub fn alive(pid: u32) -> bool {
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        return true;
+    }
+    // The process exists, but belongs to someone else
+    return io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_changed_lines() {
+        assert_eq!(changed_lines("a\nb\nc", "a\nb\nc"), Vec::<i32>::new());
+        assert_eq!(changed_lines("a\nb\nc", "a\nx\nc"), vec![1]);
+        assert_eq!(changed_lines("a\nb\nc", "x\na\nb\nc"), vec![0]);
+        assert_eq!(changed_lines("a\nb\nc", "a\nb\nc\nd"), vec![3]);
+        assert_eq!(changed_lines("a\nb\nc", "a\nc"), Vec::<i32>::new());
+        assert_eq!(changed_lines("a\nb\nc\nd", "b\na\nd\nc"), vec![1, 3]);
+        assert_eq!(changed_lines("", "a\nb"), vec![0, 1]);
+    }
+
+    #[test]
+    fn names_copies_apart() {
+        let names = ["/a%b", "/a/b", "/a%2Fb"].map(|path| file_for(path).unwrap());
+        assert_ne!(names[0], names[1]);
+        assert_ne!(names[1], names[2]);
+        assert_ne!(names[0], names[2]);
+        assert_eq!(names[1].file_name().unwrap(), "%2Fa%2Fb");
+    }
+}
@@ -29,51 +29,45 @@ pub enum Color {
 }
 
 impl Color {
-    pub fn foreground(self) -> String {
-        match self {
-            Color::Unset   => "39".to_string(),
-            Color::Black   => "30".to_string(),
-            Color::Red     => "31".to_string(),
-            Color::Green   => "32".to_string(),
-            Color::Yellow  => "33".to_string(),
-            Color::Blue    => "34".to_string(),
-            Color::Magenta => "35".to_string(),
-            Color::Cyan    => "36".to_string(),
-            Color::White   => "37".to_string(),
-            Color::BrightBlack   => "90".to_string(),
-            Color::BrightRed     => "91".to_string(),
-            Color::BrightGreen   => "92".to_string(),
-            Color::BrightYellow  => "93".to_string(),
-            Color::BrightBlue    => "94".to_string(),
-            Color::BrightMagenta => "95".to_string(),
-            Color::BrightCyan    => "96".to_string(),
-            Color::BrightWhite   => "97".to_string(),
-            Color::Color256(i) => format!("38;5;{}",i),
-            Color::RGB(r,g,b) => format!("38;2;{};{};{}",r,g,b)
-        }
+    /** Writes the SGR parameter setting the color as the foreground */
+    pub fn write_foreground(self, out: &mut Vec<u8>) {
+        self.write_parameter(30, out);
     }
-    pub fn background(self) -> String {
-        match self {
-            Color::Unset   => "49".to_string(),
-            Color::Black   => "40".to_string(),
-            Color::Red     => "41".to_string(),
-            Color::Green   => "42".to_string(),
-            Color::Yellow  => "43".to_string(),
-            Color::Blue    => "44".to_string(),
-            Color::Magenta => "45".to_string(),
-            Color::Cyan    => "46".to_string(),
-            Color::White   => "47".to_string(),
-            Color::BrightBlack   => "100".to_string(),
-            Color::BrightRed     => "101".to_string(),
-            Color::BrightGreen   => "102".to_string(),
-            Color::BrightYellow  => "103".to_string(),
-            Color::BrightBlue    => "104".to_string(),
-            Color::BrightMagenta => "105".to_string(),
-            Color::BrightCyan    => "106".to_string(),
-            Color::BrightWhite   => "107".to_string(),
-            Color::Color256(i) => format!("48;5;{}",i),
-            Color::RGB(r,g,b) => format!("48;2;{};{};{}",r,g,b)
-        }
+    /** Writes the SGR parameter setting the color as the background */
+    pub fn write_background(self, out: &mut Vec<u8>) {
+        self.write_parameter(40, out);
+    }
+
+    /** Writes the SGR parameter of the color, `base` being 30 for the foreground and 40 for the background */
+    fn write_parameter(self, base: u8, out: &mut Vec<u8>) {
+        let code = match self {
+            Color::Unset   => 9,
+            Color::Black   => 0,
+            Color::Red     => 1,
+            Color::Green   => 2,
+            Color::Yellow  => 3,
+            Color::Blue    => 4,
+            Color::Magenta => 5,
+            Color::Cyan    => 6,
+            Color::White   => 7,
+            Color::BrightBlack   => 60,
+            Color::BrightRed     => 61,
+            Color::BrightGreen   => 62,
+            Color::BrightYellow  => 63,
+            Color::BrightBlue    => 64,
+            Color::BrightMagenta => 65,
+            Color::BrightCyan    => 66,
+            Color::BrightWhite   => 67,
+            Color::Color256(i) => {
+                write!(out, "{};5;{}", base+8, i).unwrap();
+                return;
+            }
+            Color::RGB(r,g,b) => {
+                write!(out, "{};2;{};{};{}", base+8, r, g, b).unwrap();
+                return;
+            }
+        };
+        write!(out, "{}", base+code).unwrap();
     }
 }
 
@@ -129,91 +123,86 @@ impl Style {
     }
 
     pub fn to_string(self) -> String {
-        let mut s = String::new();
-        
-        s += &format!(
-            "\x1b[{};{}m",
-            self.fg.background(),
-            self.bg.background()
-        );
-        
-        if self.bold {
-            s += "\x1b[1m";
-        }
-        if self.faint {
-            s += "\x1b[2m";
-        }
-        if self.italic {
-            s += "\x1b[3m";
-        }
-        if self.underline {
-            s += "\x1b[4m";
-        }
-        if self.reverse {
-            s += "\x1b[7m";
-        }
-        if self.strike {
-            s += "\x1b[9m";
-        }
-
-        return s;
+        let mut out = Vec::new();
+        self.write_sgr(Style::default(), true, &mut out);
+        return String::from_utf8(out).unwrap();
     }
 
-    pub fn diff_to_string(self, other: Style) -> String {
-        if self == other { return String::new(); }
-        
-        let mut s = "\x1b[".to_string();
+    /** Writes the shortest SGR sequence going from the `from` style to this one, nothing if they are the same */
+    pub fn write_diff(self, from: Style, out: &mut Vec<u8>) {
+        if self == from { return; }
+        let start = out.len();
+        self.write_sgr(from, false, out);
+        // Starting over from a reset is shorter when most of the attributes get turned off
+        let mid = out.len();
+        self.write_sgr(from, true, out);
+        if out.len() - mid < mid - start {
+            out.copy_within(mid.., start);
+            out.truncate(start + out.len() - mid);
+        } else {
+            out.truncate(mid);
+        }
+    }
 
-        let mut prev: bool = false;
+    /** Writes the SGR sequence setting the attributes that differ from `from`, after resetting all of them if `reset` */
+    fn write_sgr(self, from: Style, reset: bool, out: &mut Vec<u8>) {
+        let from = if reset { Style::default() } else { from };
+        out.extend_from_slice(b"\x1b[");
+        let mut first = true;
+        let mut param = |out: &mut Vec<u8>| {
+            if !first { out.push(b';'); }
+            first = false;
+        };
 
-        if self.fg != other.fg {
-            s += &self.fg.foreground();
-            if self.bg != other.bg {
-                s += ";";
-                s += &self.bg.background();
-            }
-            prev = true;
+        if reset {
+            param(out);
+            out.push(b'0');
         }
-        else if self.bg != other.bg {
-            s += &self.bg.background();
-            prev = true;
+        if self.fg != from.fg {
+            param(out);
+            self.fg.write_foreground(out);
         }
-        
-        if self.bold != other.bold {
-            if prev { s += ";" }
-            s += if self.bold {"1"} else {"22"};
-            prev = true;
+        if self.bg != from.bg {
+            param(out);
+            self.bg.write_background(out);
         }
-        if self.faint != other.faint {
-            if prev { s += ";" }
-            s += if self.faint {"2"} else {"22"};
-            prev = true;
+        // Bold and faint are turned off together
+        let normal = from.bold && !self.bold || from.faint && !self.faint;
+        if normal {
+            param(out);
+            out.extend_from_slice(b"22");
         }
-        if self.italic != other.italic {
-            if prev { s += ";" }
-            s += if self.italic {"3"} else {"23"};
-            prev = true;
+        if self.bold && (normal || !from.bold) {
+            param(out);
+            out.push(b'1');
         }
-        if self.underline != other.underline {
-            if prev { s += ";" }
-            s += if self.underline {"4"} else {"4"};
-            prev = true;
+        if self.faint && (normal || !from.faint) {
+            param(out);
+            out.push(b'2');
         }
-        if self.reverse != other.reverse {
-            if prev { s += ";" }
-            s += if self.reverse {"7"} else {"27"};
-            prev = true;
+        if self.italic != from.italic {
+            param(out);
+            out.extend_from_slice(if self.italic {b"3"} else {b"23"});
         }
-        if self.strike != other.strike {
-            if prev { s += ";" }
-            s += if self.strike {"9"} else {"29"};
-            // prev = true;
+        if self.underline != from.underline {
+            param(out);
+            out.extend_from_slice(if self.underline {b"4"} else {b"24"});
         }
-
-        return s+"m";
+        if self.reverse != from.reverse {
+            param(out);
+            out.extend_from_slice(if self.reverse {b"7"} else {b"27"});
+        }
+        if self.strike != from.strike {
+            param(out);
+            out.extend_from_slice(if self.strike {b"9"} else {b"29"});
+        }
+        out.push(b'm');
     }
 }
 
+/** Placeholder for the cell covered by the right half of a wide character, it is never drawn */
+pub const CONTINUATION: char = '\u{FFFF}';
+
 #[derive(Clone, Copy, PartialEq)]
 pub struct Cell {
     pub c: char,
@@ -236,9 +225,23 @@ pub struct Buff {
     pub height: u32,
 }
 
+/// Start and end of a synchronized update
+const SYNC_BEGIN: &[u8] = b"\x1b[?2026h";
+const SYNC_END: &[u8] = b"\x1b[?2026l";
+
+/// Size assumed when the terminal can't tell its own
+const FALLBACK_SIZE: (u32, u32) = (80, 24);
+
+/** The size of the terminal, `FALLBACK_SIZE` if it can't be queried (when the output is not a terminal) */
+pub fn screen_size() -> (u32, u32) {
+    return terminal_size()
+        .map(|(Width(w), Height(h))| (w as u32, h as u32))
+        .filter(|(w, h)| *w > 0 && *h > 0)
+        .unwrap_or(FALLBACK_SIZE);
+}
+
 impl Buff {
-    pub fn empty() -> Buff {
-        let (Width(w),Height(h)) = terminal_size().unwrap();
+    pub fn empty(w: u32, h: u32) -> Buff {
         Buff {
             cells: vec![Cell::empty(); w as usize * h as usize],
             width: w as u32,
@@ -257,6 +260,13 @@ impl Buff {
 pub struct Renderer {
     pub backbuffer: Buff,
     pub buffer: Buff,
+    /// Size of the screen the buffer gets cleared to
+    pub size: (u32, u32),
+    /// Escape sequences of the last frame, kept around to reuse the allocation
+    out: Vec<u8>,
+    /// Whether frames are sent as synchronized updates (DEC private mode 2026), which the terminal shows all at once
+    pub synchronized: bool,
+    /// Where the terminal's cursor is shown after the frame is drawn, hidden if `None`
     pub cursor: Option<(u32, u32)>
 }
 
@@ -321,21 +331,35 @@ impl TextOptions {
 
 impl Renderer {
     pub fn new() -> Renderer {
+        let size = screen_size();
         Renderer {
             backbuffer: Buff::null(),
-            buffer: Buff::empty(),
+            buffer: Buff::empty(size.0, size.1),
+            size,
+            out: Vec::new(),
+            synchronized: false,
             cursor: None,
         }
     }
     
-    /** Clears out the buffer and sets it to the appropriate size */
+    /** Clears out the buffer and sets it to the appropriate size, keeping its allocation */
     pub fn clear(&mut self) {
-        self.buffer = Buff::empty();
+        let (w, h) = self.size;
+        self.buffer.cells.clear();
+        self.buffer.cells.resize(w as usize * h as usize, Cell::empty());
+        self.buffer.width = w;
+        self.buffer.height = h;
+    }
+
+    /** Changes the size of the screen, the next render redraws all of it */
+    pub fn resize(&mut self, size: (u32, u32)) {
+        self.size = size;
+        self.void();
     }
     
-    /** Updates the back buffer to the new buffer */
+    /** Updates the back buffer to the new buffer, the buffer is left with the old frame until it gets cleared */
     pub fn flip(&mut self) {
-        self.backbuffer = self.buffer.clone();
+        std::mem::swap(&mut self.backbuffer, &mut self.buffer);
     }
 
     pub fn void(&mut self) {
@@ -453,42 +477,67 @@ impl Renderer {
     
     /** Renders the current buffer to the screen, while optimizing the process to give the best render speeds */
     pub fn render(&mut self) {
-        let mut buff = String::new();
+        self.encode();
+        stdout().write_all(&self.out).unwrap();
+        stdout().flush().unwrap();
+    }
+
+    /** The escape sequences drawing the buffer over the back buffer, only going over the cells that changed */
+    pub fn encode(&mut self) -> &[u8] {
+        let out = &mut self.out;
+        out.clear();
+        if self.synchronized {
+            out.extend_from_slice(SYNC_BEGIN);
+        }
+        let mut utf8 = [0u8; 4];
         if self.buffer.width != self.backbuffer.width || self.buffer.height != self.backbuffer.height {
+            out.extend_from_slice(b"\x1b[H");
             let mut style = Style::default();
             for y in 0 .. self.buffer.height {
                 for x in 0 .. self.buffer.width {
                     let cell = self.buffer.cells[(x + y * self.buffer.width) as usize];
-                    buff += &cell.s.diff_to_string(style);
-                    buff.push(cell.c);
+                    if cell.c == CONTINUATION {
+                        write!(out, "\x1b[{}G", x+2).unwrap();
+                        continue;
+                    }
+                    cell.s.write_diff(style, out);
+                    out.extend_from_slice(cell.c.encode_utf8(&mut utf8).as_bytes());
                     style = cell.s;
                     if !cell.c.is_ascii() {
-                        buff += &format!("\x1b[{}G",x+2);
+                        write!(out, "\x1b[{}G", x+2).unwrap();
                     }
                 }
                 if y < self.buffer.height-1 {
-                    buff.push('\n');
+                    out.push(b'\n');
                 }
             }
         } else {
             let mut style = Style::default();
+            let width = self.buffer.width as usize;
             for y in 0 .. self.buffer.height {
+                let cells = y as usize * width .. (y as usize + 1) * width;
+                if self.buffer.cells[cells.clone()] == self.backbuffer.cells[cells] {
+                    continue;
+                }
                 let mut row = false;
                 let mut streak = self.buffer.width;
                 for x in 0 .. self.buffer.width {
                     let cell = self.buffer.cells[(x + y * self.buffer.width) as usize];
                     let bcell = self.backbuffer.cells[(x + y * self.buffer.width) as usize];
                     if cell != bcell {
+                        if cell.c == CONTINUATION {
+                            continue;
+                        }
                         if !row {
-                            buff += &format!("\x1b[{};H",y+1);
+                            write!(out, "\x1b[{};H", y+1).unwrap();
                             row = true;
                         }
                         if streak+1 != x {
-                            buff += &format!("\x1b[{}G",x+1);
+                            write!(out, "\x1b[{}G", x+1).unwrap();
                         }
                         streak = x;
-                        buff += &cell.s.diff_to_string(style);
-                        buff.push(cell.c);
+                        cell.s.write_diff(style, out);
+                        out.extend_from_slice(cell.c.encode_utf8(&mut utf8).as_bytes());
                         style = cell.s;
                         if !cell.c.is_ascii() {
                             streak = self.buffer.width;
@@ -497,16 +546,15 @@ impl Renderer {
                 }
             }
         }
-        /* if buff.len() > 0 {
-            println!("{}",buff.replace("\x1b", "\x1b[33m^\x1b[39m"));
-        } */
-        /*if let Some((x,y)) = self.cursor {
-            buff += &format!("\x1b[?25h\x1b[{};{}H",y+1,x+1);
+        if let Some((x, y)) = self.cursor {
+            write!(out, "\x1b[?25h\x1b[{};{}H", y+1, x+1).unwrap();
         } else {
-            buff += "\x1b[?25l\x1b[H";
-        }*/
-        buff += "\x1b[m";
-        stdout().write(buff.as_bytes()).unwrap();
-        stdout().flush().unwrap();
+            write!(out, "\x1b[?25l\x1b[{};1H", self.buffer.height).unwrap();
+        }
+        out.extend_from_slice(b"\x1b[m");
+        if self.synchronized {
+            out.extend_from_slice(SYNC_END);
+        }
+        return &self.out;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,296 @@
+use std::{io::{self, stdout, Write}, panic, sync::{atomic::{AtomicBool, AtomicI32, Ordering}, OnceLock}, time::{Duration, Instant}};
+use libc::{c_int, termios};
+
+use crate::input;
+
+/// Switches to the alternate screen, enables the SGR mouse reporting of clicks and drags, bracketed paste and focus events
+const ENTER: &str = "\x1b[?1049h\x1b[?1002h\x1b[?1006h\x1b[?2004h\x1b[?1004h";
+/// Undoes `ENTER`, ends a synchronized update that might have been cut short, and shows the cursor back
+const LEAVE: &str = "\x1b[?1004l\x1b[?2004l\x1b[?1006l\x1b[?1002l\x1b[?1049l\x1b[?2026l\x1b[?25h";
+
+/// How long the terminal gets to answer queries
+const QUERY_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Signals that end the process right away, after which the terminal has to be restored.
+/// SIGSEGV and SIGBUS are left to Rust, which reports stack overflows through them.
+const FATAL_SIGNALS: [c_int; 3] = [libc::SIGINT, libc::SIGQUIT, libc::SIGABRT];
+/// Signals asking the editor to quit, which it does once it had a chance to save what it can (see `terminated`)
+const TERMINATION_SIGNALS: [c_int; 2] = [libc::SIGTERM, libc::SIGHUP];
+
+/// The settings of the terminal before it was set up for the editor
+static ORIGINAL: OnceLock<termios> = OnceLock::new();
+/// Whether the terminal is currently set up for the editor
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// The termination signal received, 0 if none
+static TERMINATED: AtomicI32 = AtomicI32::new(0);
+/// Whether the terminal was resized since it was last checked
+static RESIZED: AtomicBool = AtomicBool::new(false);
+/// Whether the process was continued (after being suspended) since it was last checked
+static CONTINUED: AtomicBool = AtomicBool::new(false);
+/// The ends of the pipe waking up `Terminal::wait`, written to by signal handlers (-1 until it is opened)
+static WAKE_READ: AtomicI32 = AtomicI32::new(-1);
+static WAKE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+/**
+ * The terminal set up for the editor: raw input, in the alternate screen.
+ * Dropping it puts the terminal back the way it was, which also happens on panics and fatal signals.
+ */
+pub struct Terminal {
+    _private: (),
+}
+
+impl Terminal {
+    /** Puts the terminal in raw mode and switches to the alternate screen */
+    pub fn enter() -> io::Result<Terminal> {
+        if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 0 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "the output is not a terminal"));
+        }
+        let mut termios = core::mem::MaybeUninit::uninit();
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ORIGINAL.get_or_init(|| unsafe { termios.assume_init() });
+
+        if WAKE_READ.load(Ordering::SeqCst) < 0 {
+            let mut fds = [0 as c_int; 2];
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            WAKE_READ.store(fds[0], Ordering::SeqCst);
+            WAKE_WRITE.store(fds[1], Ordering::SeqCst);
+        }
+
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            // Restoring first, so that the message ends up on the normal screen
+            let _ = stdout().flush();
+            restore();
+            default_hook(info);
+        }));
+        for signal in FATAL_SIGNALS {
+            handle(signal, on_fatal_signal);
+        }
+        for signal in TERMINATION_SIGNALS {
+            handle(signal, on_termination_signal);
+        }
+        handle(libc::SIGWINCH, on_resize);
+        handle(libc::SIGCONT, on_continue);
+
+        let terminal = Terminal { _private: () };
+        terminal.resume()?;
+        return Ok(terminal);
+    }
+
+    /** Sets the terminal up for the editor again, after it was given back to the shell (see `suspend`) */
+    pub fn resume(&self) -> io::Result<()> {
+        let mut raw = *ORIGINAL.get().unwrap();
+        // No signals (Ctrl-C, Ctrl-Z, Ctrl-\), flow control (Ctrl-S, Ctrl-Q), Ctrl-V, or CR to LF translation,
+        // so that every key makes it to the editor as typed
+        raw.c_iflag &= !(libc::IGNBRK | libc::BRKINT | libc::PARMRK | libc::ISTRIP | libc::INLCR | libc::IGNCR | libc::ICRNL | libc::IXON);
+        raw.c_lflag &= !(libc::ECHO | libc::ECHONL | libc::ICANON | libc::ISIG | libc::IEXTEN);
+        raw.c_cflag &= !(libc::CSIZE | libc::PARENB);
+        raw.c_cflag |= libc::CS8;
+        // Output processing is left on, the renderer relies on '\n' going back to the start of the line
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ACTIVE.store(true, Ordering::SeqCst);
+        print!("{}", ENTER);
+        stdout().flush()?;
+        return Ok(());
+    }
+
+    /**
+     * Blocks until there is input to read, `wake` gets called (signals call it too), or `deadline` passes.
+     * Returns whether there is input, stdin is left out when `input` is false.
+     */
+    pub fn wait(&self, input: bool, deadline: Option<Instant>) -> io::Result<bool> {
+        let wake = WAKE_READ.load(Ordering::SeqCst);
+        let mut fds = [
+            libc::pollfd { fd: if input { libc::STDIN_FILENO } else { -1 }, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: wake, events: libc::POLLIN, revents: 0 },
+        ];
+        let timeout = match deadline {
+            // Rounded up, waking up early would only mean going back to sleep right away
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()).as_micros().div_ceil(1000).min(c_int::MAX as u128) as c_int,
+            None => -1,
+        };
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            let err = io::Error::last_os_error();
+            // A signal came in, which is as good a reason to wake up as any
+            return if err.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(err) };
+        }
+        if fds[1].revents != 0 {
+            let mut buf = [0u8; 64];
+            while unsafe { libc::read(wake, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+        }
+        return Ok(fds[0].revents != 0);
+    }
+
+    /**
+     * Asks the terminal whether it supports a DEC private mode (with DECRQM).
+     * The device attributes get asked for right after, which every terminal answers,
+     * so that the ones that don't know about DECRQM are told apart without waiting out `QUERY_TIMEOUT`.
+     * Input coming in meanwhile is added to `input`.
+     */
+    pub fn supports(&self, mode: u32, input: &mut Vec<u8>) -> bool {
+        print!("\x1b[?{}$p\x1b[c", mode);
+        if stdout().flush().is_err() {
+            return false;
+        }
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let mut parser = input::Parser::new();
+        let mut buf = [0u8; 256];
+        let mut state = None;
+        let mut answered = false;
+        while !answered && Instant::now() < deadline {
+            match self.wait(true, Some(deadline)) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(_) => break,
+            }
+            match self.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => parser.bytes.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+            for (_, seq) in parser.parse() {
+                if let Some((m, s)) = input::mode_report(&seq) {
+                    if m == mode {
+                        state = Some(s);
+                    }
+                } else if input::is_device_attributes(&seq) {
+                    answered = true;
+                } else {
+                    input.extend_from_slice(&seq);
+                }
+            }
+        }
+        input.extend_from_slice(&parser.bytes);
+        // 1 and 2 are set and reset, 3 is permanently set, 0 (unknown) and 4 (permanently reset) mean it can't be used
+        return matches!(state, Some(1..=3));
+    }
+
+    /** Reads what is available of the input, without going through the buffering of `io::stdin` (which `wait` wouldn't see) */
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(n as usize);
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = stdout().flush();
+        restore();
+    }
+}
+
+/**
+ * Puts the terminal back the way it was, if it is still set up for the editor.
+ * Only does async-signal-safe calls, so that it can be used from signal handlers.
+ */
+fn restore() {
+    if !ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    unsafe {
+        libc::write(libc::STDOUT_FILENO, LEAVE.as_ptr() as *const libc::c_void, LEAVE.len());
+        if let Some(original) = ORIGINAL.get() {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+        }
+    }
+}
+
+/** The termination signal (SIGTERM or SIGHUP) received, if any */
+pub fn terminated() -> Option<c_int> {
+    let signal = TERMINATED.load(Ordering::SeqCst);
+    return (signal != 0).then_some(signal);
+}
+
+/**
+ * Wakes up `Terminal::wait`, for threads with something for the main loop.
+ * Async-signal-safe.
+ */
+pub fn wake() {
+    let fd = WAKE_WRITE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe {
+            libc::write(fd, [0u8].as_ptr() as *const libc::c_void, 1);
+        }
+    }
+}
+
+/** Whether the terminal was resized since the last call */
+pub fn resized() -> bool {
+    return RESIZED.swap(false, Ordering::SeqCst);
+}
+
+/**
+ * Gives the terminal back the way it was and stops the process, as Ctrl-Z would outside of raw mode.
+ * Returns once the process is continued, which `continued` then reports.
+ */
+pub fn suspend() {
+    let _ = stdout().flush();
+    restore();
+    unsafe {
+        libc::raise(libc::SIGTSTP);
+    }
+    // Also when the signal was ignored (by shells without job control), so that the terminal gets set back up
+    CONTINUED.store(true, Ordering::SeqCst);
+}
+
+/** Whether the process was continued since the last call, in which case the terminal needs to be set up again */
+pub fn continued() -> bool {
+    return CONTINUED.swap(false, Ordering::SeqCst);
+}
+
+/** Restores the terminal, then ends the process the way the signal would have */
+pub fn reraise(signal: c_int) -> ! {
+    restore();
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+        libc::_exit(128 + signal);
+    }
+}
+
+/** Sets the function called when the process receives the signal */
+fn handle(signal: c_int, handler: extern "C" fn(c_int)) {
+    unsafe {
+        let mut action: libc::sigaction = core::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        // Blocking calls that are left (writes to the terminal, file I/O) carry on instead of failing when a signal comes in;
+        // poll fails with EINTR regardless, which is what wakes the main loop up
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, core::ptr::null_mut());
+    }
+}
+
+extern "C" fn on_fatal_signal(signal: c_int) {
+    reraise(signal);
+}
+
+extern "C" fn on_termination_signal(signal: c_int) {
+    TERMINATED.store(signal, Ordering::SeqCst);
+    wake();
+}
+
+extern "C" fn on_resize(_signal: c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+    wake();
+}
+
+extern "C" fn on_continue(_signal: c_int) {
+    CONTINUED.store(true, Ordering::SeqCst);
+    // The terminal may have changed size while the editor was stopped
+    RESIZED.store(true, Ordering::SeqCst);
+    wake();
+}